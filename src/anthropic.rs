@@ -3,7 +3,8 @@ use eventsource_stream::{Event, Eventsource};
 use futures::StreamExt;
 use reqwest::header::{HeaderMap, HeaderValue, CONTENT_TYPE};
 use serde_json::{json, Value};
-use crate::common::{write, Cli, Exchange, Tool, ToolUse};
+use crate::common::{compose_system_prompt, current_model, tools, Cli, Exchange, ToolUse};
+use crate::events::Observer;
 
 fn serialize_assistant_response(message: &str, tool_use: &[ToolUse]) -> Value {
     let mut content_block = vec![];
@@ -30,13 +31,18 @@ fn serialize_tool_results(tool_use: &[ToolUse]) -> Value {
         "is_error": is_error
     });
 
-    let tool_results = tool_use.into_iter().map(serialize_tool_result).collect::<Vec<_>>();
+    let tool_results = tool_use.iter().map(serialize_tool_result).collect::<Vec<_>>();
     json!({ "role": "user", "content": tool_results })
 }
 
-fn build_request_body(exchanges: &[Exchange], current: &Exchange) -> serde_json::Value {
+pub fn build_request_body(exchanges: &[Exchange], current: &Exchange) -> Result<Value> {
+    let cli: Cli = clap::Parser::parse();
+    // drop whole exchanges so that tool uses stay paired with their results
+    let skip = exchanges.len().saturating_sub(cli.max_context_messages.unwrap_or(usize::MAX));
+    let exchanges = &exchanges[skip..];
+
     let mut messages = vec![];
-    for Exchange { prompt, response } in exchanges.into_iter().chain([current]) {
+    for Exchange { prompt, response } in exchanges.iter().chain([current]) {
         messages.push(json!({ "role": "user", "content": prompt }));
         for (message, tool_use) in response {
             messages.push(serialize_assistant_response(message, tool_use));
@@ -46,28 +52,22 @@ fn build_request_body(exchanges: &[Exchange], current: &Exchange) -> serde_json:
         }
     }
 
-    let bash_tool = Tool {
-        name: "bash",
-        description: include_str!("./resources/bash-description.txt"),
-        input_schema: include_str!("./resources/bash-schema.json")
-    };
-
-    let text_editor_tool = Tool {
-        name: "text_editor",
-        description: include_str!("./resources/text_editor-description.txt"),
-        input_schema: include_str!("./resources/text_editor-schema.json")
-    };
-
-    let Cli { temperature, max_tokens, model, .. } = clap::Parser::parse();
-    return json!({
+    let system = compose_system_prompt(&cli)?;
+    let model = current_model(&cli);
+    let Cli { temperature, max_tokens, no_tools, .. } = cli;
+    let mut body = json!({
         "model": model,
         "max_tokens": max_tokens.unwrap_or(2048),
         "temperature": temperature.unwrap_or(1.0),
         "stream": true,
-        "system": include_str!("resources/system-prompt.txt"),
-        "tools": [bash_tool, text_editor_tool],
+        "system": system,
         "messages": messages
     });
+    if !no_tools {
+        body["tools"] = json!(tools(&cli)?);
+    }
+
+    Ok(body)
 }
 
 pub async fn send_request(exchanges: &[Exchange], current: &Exchange) -> Result<reqwest::Response> {
@@ -79,7 +79,7 @@ pub async fn send_request(exchanges: &[Exchange], current: &Exchange) -> Result<
     headers.insert("anthropic-version", HeaderValue::from_static("2023-06-01"));
 
     let url = "https://api.anthropic.com/v1/messages";
-    let body = build_request_body(exchanges, current).to_string();
+    let body = build_request_body(exchanges, current)?.to_string();
     let request = reqwest::Client::new().post(url).headers(headers).body(body);
 
     let response = request.send().await?;
@@ -88,7 +88,25 @@ pub async fn send_request(exchanges: &[Exchange], current: &Exchange) -> Result<
         let message = response.text().await.unwrap_or_else(|error| format!("{error:?}"));
         bail!("Failed with status code: {status}: {message}");
     }
-    return Ok(response);
+
+    let Cli { show_ratelimits, .. } = clap::Parser::parse();
+    if show_ratelimits {
+        print_ratelimits(response.headers());
+    }
+    Ok(response)
+}
+
+// e.g. "anthropic-ratelimit-tokens-remaining: 79000" is shown as "tokens-remaining=79000"
+fn print_ratelimits(headers: &HeaderMap) {
+    let limits = headers.iter()
+        .filter_map(|(name, value)| {
+            let name = name.as_str().strip_prefix("anthropic-ratelimit-")?;
+            Some(format!("{name}={}", value.to_str().unwrap_or("?")))
+        })
+        .collect::<Vec<_>>();
+    if !limits.is_empty() {
+        eprintln!("[ratelimits] {}", limits.join(" "));
+    }
 }
 
 fn parse_tool_use_content_block_start(response: &Value) -> Result<ToolUse> {
@@ -97,8 +115,11 @@ fn parse_tool_use_content_block_start(response: &Value) -> Result<ToolUse> {
     Ok(ToolUse { name, id, ..Default::default() })
 }
 
-async fn stream_response_message(Event { event, data, .. }: Event, message: &mut String)
--> Result<Option<ToolUse>> {
+fn stream_response_message(
+    Event { event, data, .. }: Event,
+    message: &mut String,
+    observer: &dyn Observer
+) -> Result<Option<ToolUse>> {
     let response = serde_json::from_str::<Value>(&data).context("Data not valid JSON.")?;
 
     if response["content_block"]["type"].as_str() == Some("tool_use") {
@@ -109,14 +130,18 @@ async fn stream_response_message(Event { event, data, .. }: Event, message: &mut
         let tokens =
             response["delta"]["text"].as_str().context("Tokens not found in content block.")?;
         message.push_str(tokens);
-        write(tokens).await.context("Failed to output tokens.")?;
-    } else if event == "content_block_stop" {
-        print!("\n\n");
+        observer.on_token(tokens);
+    } else if event == "content_block_start" && !message.is_empty() {
+        // only between blocks, the caller ends the message once it knows no continuation follows
+        observer.on_token("\n\n");
     }
 
     Ok(None)
 }
 
+// generous enough for creating large files, but bounds memory if a stream never ends
+const MAX_TOOL_INPUT_LEN: usize = 64 << 20;
+
 fn stream_tool_use(
     Event { event, data, .. }: Event,
     partial_json: &mut String,
@@ -129,31 +154,106 @@ fn stream_tool_use(
         return parse_tool_use_content_block_start(&response).map(Some);
     } else if event == "content_block_delta" {
         let fragment = response["delta"]["partial_json"].as_str().context("Tool input not found.")?;
+        if partial_json.len() + fragment.len() > MAX_TOOL_INPUT_LEN {
+            let name = &prev_tool_use.name;
+            bail!("The input to the {name} tool exceeded {MAX_TOOL_INPUT_LEN} bytes");
+        }
         partial_json.push_str(fragment);
     } else if event == "content_block_stop" {
-        prev_tool_use.input =
-            serde_json::from_str(&partial_json).context("Tool input not valid JSON.")?;
+        if partial_json.is_empty() {
+            partial_json.push_str("{}");
+        }
+        match serde_json::from_str(partial_json) {
+            Ok(input) => prev_tool_use.input = input,
+            Err(error) => {
+                // let the model see and correct its malformed arguments instead of aborting
+                prev_tool_use.input = json!({});
+                prev_tool_use.output =
+                    (format!("Your tool arguments weren't valid JSON: {error}").into(), true);
+                prev_tool_use.raw_input = Some(partial_json.clone());
+            }
+        }
     }
 
     Ok(None)
 }
 
-pub async fn stream_response(response: reqwest::Response) -> Result<(String, Vec<ToolUse>)> {
+fn parse_stop_reason(data: &str) -> Result<Option<String>> {
+    let response = serde_json::from_str::<Value>(data).context("Data not valid JSON.")?;
+    Ok(response["delta"]["stop_reason"].as_str().map(str::to_owned))
+}
+
+fn parse_usage(data: &str, pointer: &str) -> Result<u64> {
+    let response = serde_json::from_str::<Value>(data).context("Data not valid JSON.")?;
+    Ok(response.pointer(pointer).and_then(Value::as_u64).unwrap_or(0))
+}
+
+// a tool use's input stays null until its content block stops
+fn cut_off(error: anyhow::Error, tool_uses: &[ToolUse]) -> anyhow::Error {
+    match tool_uses.last() {
+        Some(ToolUse { name, input: Value::Null, .. }) => error
+            .context(format!("The stream ended before the input to the {name} tool was complete")),
+        _ => error.context("Failed to fetch tokens.")
+    }
+}
+
+// what the loops in stream_response do with an event after message_event
+enum Flow {
+    Stop,
+    Next,
+    ContentBlock
+}
+
+// handles the events about the message as a whole, leaving those of its content blocks to the
+// caller
+fn message_event(
+    Event { event, data, .. }: &Event,
+    input_tokens: &mut u64,
+    stop_reason: &mut Option<String>,
+    observer: &dyn Observer
+) -> Result<Flow> {
+    match event.as_str() {
+        "message_stop" => return Ok(Flow::Stop),     // don't rely on the connection closing
+        "message_start" => *input_tokens = parse_usage(data, "/message/usage/input_tokens")?,
+        "message_delta" => {
+            *stop_reason = parse_stop_reason(data)?;
+            observer.on_usage(*input_tokens, parse_usage(data, "/usage/output_tokens")?);
+        },
+        _ => return Ok(Flow::ContentBlock)
+    }
+    Ok(Flow::Next)
+}
+
+// returns the streamed message and tool uses along with the stop reason, e.g. "max_tokens"
+pub async fn stream_response(response: reqwest::Response, observer: &dyn Observer)
+-> Result<(String, Vec<ToolUse>, Option<String>)> {
     let mut message = "".to_string();
     let mut tool_uses = vec![];
+    let mut stop_reason = None;
+    let mut input_tokens = 0;
     let mut partial_json = "".to_string();
     let mut eventsource = response.bytes_stream().eventsource();
 
     while let Some(event) = eventsource.next().await {
         let event = event.context("Failed to fetch tokens.")?;
-        if let Some(tool_use) = stream_response_message(event, &mut message).await? {
+        match message_event(&event, &mut input_tokens, &mut stop_reason, observer)? {
+            Flow::Stop => return Ok((message, tool_uses, stop_reason)),
+            Flow::Next => continue,
+            Flow::ContentBlock => ()
+        }
+        if let Some(tool_use) = stream_response_message(event, &mut message, observer)? {
             tool_uses.push(tool_use);
             break;
         }
     }
 
     while let Some(event) = eventsource.next().await {
-        let event = event.context("Failed to fetch tokens.")?;
+        let event = event.map_err(|error| cut_off(error.into(), &tool_uses))?;
+        match message_event(&event, &mut input_tokens, &mut stop_reason, observer)? {
+            Flow::Stop => return Ok((message, tool_uses, stop_reason)),
+            Flow::Next => continue,
+            Flow::ContentBlock => ()
+        }
         let prev_tool_use =
             tool_uses.last_mut().expect("The previous while loop appends to tool_uses.");
         if let Some(tool_use) = stream_tool_use(event, &mut partial_json, prev_tool_use)? {
@@ -161,5 +261,59 @@ pub async fn stream_response(response: reqwest::Response) -> Result<(String, Vec
         }
     }
 
-    Ok((message, tool_uses))
+    if let Some(ToolUse { name, input: Value::Null, .. }) = tool_uses.last() {
+        bail!("The stream ended before the input to the {name} tool was complete");
+    }
+    Ok((message, tool_uses, stop_reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sse(event: &str, data: Value) -> String {
+        format!("event: {event}\ndata: {data}\n\n")
+    }
+
+    #[tokio::test]
+    async fn multi_megabyte_tool_input() {
+        let file_text = "x".repeat(3 << 20);
+        let input = json!({ "command": "create", "path": "/big", "file_text": file_text });
+        let input = input.to_string();
+        let tool_use = json!({ "type": "tool_use", "id": "toolu_1", "name": "text_editor" });
+        let mut body = sse("content_block_start", json!({ "content_block": tool_use }));
+        // one event far larger than the chunks the body arrives in, then many small ones
+        let (head, tail) = input.split_at(input.len() / 2);
+        body += &sse("content_block_delta", json!({ "delta": { "partial_json": head } }));
+        for fragment in tail.as_bytes().chunks(4096) {
+            let fragment = std::str::from_utf8(fragment).unwrap();
+            body += &sse("content_block_delta", json!({ "delta": { "partial_json": fragment } }));
+        }
+        body += &sse("content_block_stop", json!({}));
+        let chunks = body.as_bytes().chunks(16384).map(Ok::<_, std::io::Error>);
+        let mut events = futures::stream::iter(chunks).eventsource();
+
+        let mut partial_json = String::new();
+        let mut tool_uses = vec![ToolUse::default()];
+        while let Some(event) = events.next().await {
+            let prev_tool_use = tool_uses.last_mut().unwrap();
+            let tool_use = stream_tool_use(event.unwrap(), &mut partial_json, prev_tool_use);
+            tool_uses.extend(tool_use.unwrap());
+        }
+        let tool_use = tool_uses.last().unwrap();
+        assert_eq!(tool_use.name, "text_editor");
+        assert_eq!(tool_use.input["file_text"].as_str().map(str::len), Some(3 << 20));
+    }
+
+    #[test]
+    fn tool_input_over_the_cap() {
+        let mut partial_json = "x".repeat(MAX_TOOL_INPUT_LEN - 1);
+        let mut tool_use = ToolUse { name: "text_editor".into(), ..Default::default() };
+        let data = json!({ "delta": { "partial_json": "ab" } }).to_string();
+        let event = Event { event: "content_block_delta".into(), data, ..Default::default() };
+        let error = stream_tool_use(event, &mut partial_json, &mut tool_use).unwrap_err();
+        assert_eq!(error.to_string(),
+            format!("The input to the text_editor tool exceeded {MAX_TOOL_INPUT_LEN} bytes"));
+        assert_eq!(partial_json.len(), MAX_TOOL_INPUT_LEN - 1);
+    }
 }
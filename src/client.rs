@@ -1,44 +1,163 @@
+use std::{collections::{HashMap, HashSet}, sync::Mutex, time::{Duration, Instant}};
 use anyhow::{bail, Context, Result};
 use serde_json::Value;
-use tonic::{transport::Channel, Request};
+use tonic::{transport::{Channel, Endpoint}, Request};
+use crate::common::Cli;
 use bash_agent::{
-    tool_runner_client::ToolRunnerClient as Client, BashRequest, CreateRequest, InsertRequest,
-    StringReplaceRequest, UndoEditRequest, ViewRange, ViewRequest
+    tool_runner_client::ToolRunnerClient as Client, BashRequest, BashResponse, CreateRequest,
+    CreateResponse, InsertRequest, Snippet, StringReplaceRequest, UndoEditRequest, ViewRange,
+    ViewRequest
 };
 
 mod bash_agent {
     tonic::include_proto!("bash_agent");
     impl Snippet {
-        pub fn to_string_numbered(self) -> String {
-            self.lines.into_iter().enumerate()
-                .map(|(i, line)| format!("{}: {line}", self.start as usize + i))
+        // lines added by an edit get a "+" in the gutter
+        pub fn to_string_numbered(&self) -> String {
+            let changed = self.changed_start as usize..self.changed_end as usize;
+            let gutter = |number| match (changed.is_empty(), changed.contains(&number)) {
+                (true, _) => "",
+                (false, true) => "+ ",
+                (false, false) => "  "
+            };
+            self.lines.iter().enumerate()
+                .map(|(i, line)| (self.start as usize + i, line))
+                .map(|(number, line)| format!("{}{number}: {line}", gutter(number)))
                 .collect::<Vec<_>>().join("\n")
         }
     }
 }
 
-async fn client() -> Result<Client<Channel>> {
-    let crate::common::Cli { server, .. } = clap::Parser::parse();
-    Client::connect(format!("http://{server}:50051")).await.context("Failed to connect to server")
+#[derive(Default)]
+struct EditStats {
+    files: HashSet<String>,
+    lines_added: u64,
+    lines_removed: u64
+}
+
+lazy_static::lazy_static! {
+    static ref edit_stats: Mutex<EditStats> = Default::default();
+    static ref channels: tokio::sync::Mutex<HashMap<String, Channel>> = Default::default();
+    static ref last_output: Mutex<Option<String>> = Default::default();
+    static ref cached_views: Mutex<HashMap<String, String>> = Default::default();
+}
+
+fn record_edit(path: &str, lines_added: u64, lines_removed: u64) {
+    let mut stats = edit_stats.lock().unwrap_or_else(|error| error.into_inner());
+    stats.files.insert(path.into());
+    stats.lines_added += lines_added;
+    stats.lines_removed += lines_removed;
+}
+
+/// Summarizes the edits the text_editor tool has made this session.
+pub fn edit_summary() -> String {
+    let stats = edit_stats.lock().unwrap_or_else(|error| error.into_inner());
+    let files = match stats.files.len() {
+        1 => "1 file".to_string(),
+        count => format!("{count} files")
+    };
+    format!("Edited {files}, +{}/-{} lines this session.", stats.lines_added, stats.lines_removed)
+}
+
+// the model may only get a truncated copy, so this holds on to the whole thing
+fn keep_output(output: &str) {
+    *last_output.lock().unwrap_or_else(|error| error.into_inner()) = Some(output.into());
+}
+
+/// The untruncated output of the latest tool call that succeeded, if any.
+pub fn latest_output() -> Option<String> {
+    last_output.lock().unwrap_or_else(|error| error.into_inner()).clone()
+}
+
+// a call the model got wrong, it goes back to the model as a tool error instead of ending the
+// exchange
+#[derive(Debug, thiserror::Error)]
+#[error("{0}")]
+struct InvalidInput(String);
+
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(30);
+const KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// each server's channel is shared across tool calls, pings keep it alive while idle and it
+// reconnects by itself if the connection breaks anyway
+async fn client(server: Option<&str>) -> Result<Client<Channel>> {
+    let Cli { servers, .. } = clap::Parser::parse();
+    let server = match server {
+        Some(server) if servers.iter().any(|configured| configured == server) => server,
+        Some(server) => {
+            let message = format!("{server} isn't one of the servers: {}", servers.join(", "));
+            bail!(InvalidInput(message));
+        }
+        None => servers.first().context("--server is required to call tools")?
+    };
+
+    let mut cached = channels.lock().await;
+    if let Some(cached) = cached.get(server) {
+        return Ok(Client::new(cached.clone()));
+    }
+    let connected = Endpoint::from_shared(format!("http://{server}:50051"))?
+        .http2_keep_alive_interval(KEEPALIVE_INTERVAL)
+        .keep_alive_timeout(KEEPALIVE_TIMEOUT)
+        .keep_alive_while_idle(true)
+        .connect().await.with_context(|| format!("Failed to connect to server {server}"))?;
+    Ok(Client::new(cached.entry(server.into()).or_insert(connected).clone()))
+}
+
+/// Has every server start a fresh shell and forget its edit history.
+pub async fn reset_servers() -> Result<()> {
+    let Cli { servers, .. } = clap::Parser::parse();
+    for server in &servers {
+        client(Some(server)).await?.reset(Request::new(())).await?;
+    }
+    Ok(())
+}
+
+// keeps the start and the end of the output since errors tend to show up last
+fn truncate_middle(output: String, max_chars: usize) -> String {
+    let length = output.chars().count();
+    if length <= max_chars {
+        return output;
+    }
+
+    let head = output.chars().take(max_chars / 2).collect::<String>();
+    let tail = output.chars().skip(length - (max_chars - max_chars / 2)).collect::<String>();
+    format!("{head}\n[... {} characters truncated ...]\n{tail}", length - max_chars)
 }
 
 async fn call_bash_tool(input: &Value) -> Result<String> {
     let Some(input) = input.as_object() else {
-        bail!("The argument(s) must be fields in a JSON object");
+        bail!(InvalidInput("The argument(s) must be fields in a JSON object".into()));
     };
-    let Some(command) = input.get("command").map(Value::as_str).flatten() else {
-        bail!("The \"command\" argument is required and must be a string");
+    let Some(command) = input.get("command").and_then(Value::as_str) else {
+        bail!(InvalidInput("The \"command\" argument is required and must be a string".into()));
     };
+    let server = input.get("server").and_then(Value::as_str);
 
-    println!("Running command `{command}.`");
+    let Cli { max_bash_output, heartbeat_interval, quiet, tui, .. } = clap::Parser::parse();
     let request = Request::new(BashRequest { input: command.into() });
-    let output = client().await?.run_bash_tool(request).await?.into_inner().output;
+    let mut client = client(server).await?;
+    let call = client.run_bash_tool(request);
+    tokio::pin!(call);
 
-    if !output.trim().is_empty() {
-        print!("{}\n\n.", output.trim());
-    }
+    // goes to stderr so it never ends up in the output the model sees, the TUI already shows the
+    // running command and stderr would draw over it
+    let start = Instant::now();
+    let heartbeat = Duration::from_secs(heartbeat_interval);
+    let BashResponse { output, elapsed_ms } = loop {
+        tokio::select! {
+            response = &mut call => break response?.into_inner(),
+            _ = tokio::time::sleep(heartbeat), if !quiet && !tui && heartbeat_interval > 0 =>
+                eprintln!("still running... ({}s elapsed)", start.elapsed().as_secs())
+        }
+    };
 
-    Ok(output)
+    keep_output(&output);
+    let truncated = output.chars().count() > max_bash_output;
+    let output = truncate_middle(output, max_bash_output);
+    Ok(match elapsed_ms {
+        Some(elapsed_ms) => format!("{output}\n[elapsed_ms={elapsed_ms} truncated={truncated}]"),
+        None => output
+    })
 }
 
 #[derive(Debug, serde::Deserialize)]
@@ -54,80 +173,215 @@ struct TextEditorInput {
     #[serde(default)]
     old_str: Option<String>,
     #[serde(default)]
-    view_range: Option<Vec<i32>>
+    view_range: Option<Vec<i32>>,
+    #[serde(default)]
+    overwrite: bool,
+    #[serde(default)]
+    preview: bool,
+    #[serde(default)]
+    server: Option<String>
 }
 
-async fn call_view(path: &str, view_range: Option<Vec<i32>>) -> Result<String> {
-    let view_range = match view_range.as_ref().map(Vec::as_slice) {
+async fn call_view(server: Option<&str>, path: &str, view_range: Option<Vec<i32>>)
+-> Result<String> {
+    let view_range = match view_range.as_deref() {
         Some([start, -1]) if start > &0 => Some(ViewRange { start: *start as u32, end: None }),
-        Some([start, end]) if start > &0 || end > &0 =>
+        Some([start, end]) if start > &0 && end > &0 =>
             Some(ViewRange { start: *start as u32, end: Some(*end as u32) }),
-        Some(_) => bail!("view_range must have two positive entries"),
+        Some(_) => bail!(InvalidInput("view_range must have two positive entries".into())),
         None => None
     };
     let request = Request::new(ViewRequest { path: path.into(), view_range });
-    let snippet = client().await?.view(request).await?.into_inner();
+    let mut snippet = client(server).await?.view(request).await?.into_inner();
 
-    Ok(format!("Here's {path} with each line numbered:\n{}", snippet.to_string_numbered()))
+    keep_output(&snippet.to_string_numbered());
+    let Cli { max_view_lines, .. } = clap::Parser::parse();
+    let hidden = snippet.lines.len().saturating_sub(max_view_lines);
+    snippet.lines.truncate(max_view_lines);
+    let mut output =
+        format!("Here's {path} with each line numbered:\n{}", snippet.to_string_numbered());
+    if hidden > 0 {
+        output += &format!("\n[... {hidden} more lines truncated, use view_range to see them ...]");
+    }
+    Ok(output)
 }
 
-async fn call_create(path: &str, file_text: Option<String>) -> Result<String> {
-    let file_text = file_text.context("file_text is required with the create command")?;
-    let request = Request::new(CreateRequest { path: path.into(), file_text });
-    client().await?.create(request).await?;
+async fn call_create(server: Option<&str>, path: &str, file_text: Option<String>, overwrite: bool)
+-> Result<String> {
+    let file_text = file_text
+        .ok_or_else(|| InvalidInput("file_text is required with the create command".into()))?;
+    let request = Request::new(CreateRequest { path: path.into(), file_text, overwrite });
+    let CreateResponse { lines_added, lines_removed } =
+        client(server).await?.create(request).await?.into_inner();
+    record_edit(path, lines_added, lines_removed);
     Ok(format!("Successfully created {path}."))
 }
 
-async fn call_str_replace(path: &str, old: Option<String>, new: Option<String>) -> Result<String> {
-    let old = old.context("old_str is required with the str_replace command")?;
+fn preview_message(path: &str, snippet: &Snippet) -> String {
+    format!("{path} was left unchanged, this is a preview of the edit. {}:\n{}",
+        "Issue the same command without preview to apply it", snippet.to_string_numbered())
+}
+
+async fn call_str_replace(
+    server: Option<&str>,
+    path: &str,
+    old: Option<String>,
+    new: Option<String>,
+    preview: bool
+) -> Result<String> {
+    let old =
+        old.ok_or_else(|| InvalidInput("old_str is required with the str_replace command".into()))?;
+    let Cli { edit_context_lines, .. } = clap::Parser::parse();
     let request = Request::new(StringReplaceRequest {
         path: path.into(),
         to_replace: old,
-        replacement: new
+        replacement: new,
+        context_lines: edit_context_lines,
+        preview
     });
-    let snippet = client().await?.string_replace(request).await?.into_inner().to_string_numbered();
+    let snippet = client(server).await?.string_replace(request).await?.into_inner();
+    if preview {
+        return Ok(preview_message(path, &snippet));
+    }
+    record_edit(path, snippet.lines_added, snippet.lines_removed);
+    let snippet = snippet.to_string_numbered();
     Ok(format!("Review the changes and make sure it's as expected, edit again if not:\n{snippet}"))
 }
 
-async fn insert(path: &str, line_number: Option<u32>, line: Option<String>) -> Result<String> {
-    let line_number = line_number.context("insert_line is required with the insert command")?;
-    let line = line.context("new_str is required with the insert command")?;
-    let request = Request::new(InsertRequest { path: path.into(), line_number, line } );
-    let snippet = client().await?.insert(request).await?.into_inner().to_string_numbered();
+async fn insert(
+    server: Option<&str>,
+    path: &str,
+    line_number: Option<u32>,
+    line: Option<String>,
+    preview: bool
+) -> Result<String> {
+    let line_number = line_number
+        .ok_or_else(|| InvalidInput("insert_line is required with the insert command".into()))?;
+    let line =
+        line.ok_or_else(|| InvalidInput("new_str is required with the insert command".into()))?;
+    let request = Request::new(InsertRequest { path: path.into(), line_number, line, preview } );
+    let snippet = client(server).await?.insert(request).await?.into_inner();
+    if preview {
+        return Ok(preview_message(path, &snippet));
+    }
+    record_edit(path, snippet.lines_added, snippet.lines_removed);
+    let snippet = snippet.to_string_numbered();
     Ok(format!("Review the change and make sure it's as expected ({}). {}:\n{snippet}",
-        "correct indentation, no duplicate lines, etc", "Edit the file if not."))
+        "correct indentation, no duplicate lines, etc",
+        "Inserted lines are marked with +, edit the file if it's wrong"))
 }
 
-async fn undo_edit(path: &str) -> Result<String> {
+async fn undo_edit(server: Option<&str>, path: &str) -> Result<String> {
     let request = Request::new(UndoEditRequest { path: path.into() } );
-    let snippet = client().await?.undo_edit(request).await?.into_inner().to_string_numbered();
-    Ok(format!("Last edit to {path} undone successfully. Please review:\n{snippet}"))
+    let snippet = client(server).await?.undo_edit(request).await?.into_inner();
+    record_edit(path, snippet.lines_added, snippet.lines_removed);
+    // undoing an edit that only added lines takes them away again, which leaves nothing to mark
+    let marked = if snippet.changed_start == snippet.changed_end {
+        "the lines it added were removed"
+    } else {
+        "restored lines are marked with +"
+    };
+    let snippet = snippet.to_string_numbered();
+    Ok(format!("Last edit to {path} undone successfully, {marked}. Please review:\n{snippet}"))
 }
 
 async fn call_text_editor_tool(input: &Value) -> Result<String> {
-    println!("Running text_editor with input {}.",
-        serde_json::to_string_pretty(input).context("Failed to parse input")?);
-
-    let TextEditorInput { command, path, file_text, insert_line, new_str, old_str, view_range } =
-        serde_json::from_value::<TextEditorInput>(input.clone()).context("Failed to parse input")?;
+    let TextEditorInput {
+        command, path, file_text, insert_line, new_str, old_str, view_range, overwrite, preview,
+        server
+    } = serde_json::from_value::<TextEditorInput>(input.clone())
+        .map_err(|error| InvalidInput(format!("Failed to parse input: {error}")))?;
 
+    let server = server.as_deref();
     let output = match command.as_str() {
-        "view" => call_view(&path, view_range).await,
-        "create" => call_create(&path, file_text).await,
-        "str_replace" => call_str_replace(&path, old_str, new_str).await,
-        "insert" => insert(&path, insert_line, new_str).await,
-        "undo_edit" => undo_edit(&path).await,
-        command => bail!("{command} is an invalid text_editor command")
-    }?;
-
-    print!("{output}\n\n");
+        "view" => return call_view(server, &path, view_range).await,
+        "create" => call_create(server, &path, file_text, overwrite).await?,
+        "str_replace" => call_str_replace(server, &path, old_str, new_str, preview).await?,
+        "insert" => insert(server, &path, insert_line, new_str, preview).await?,
+        "undo_edit" => undo_edit(server, &path).await?,
+        command => bail!(InvalidInput(format!("{command} is an invalid text_editor command")))
+    };
+    keep_output(&output);
     Ok(output)
 }
 
-pub async fn call_tool(name: &str, input: &Value) -> Result<String> {
-    match name {
+// the bool is set when the output is an error the model should see and correct, like a call to a
+// tool it made up or arguments it got wrong
+pub async fn call_tool(name: &str, input: &Value) -> Result<(String, bool)> {
+    if !["bash", "text_editor"].contains(&name) {
+        let output = format!("There is no {name} tool, only bash and text_editor are available");
+        return Ok((output, true));
+    }
+
+    let Cli { cache_tool_results, .. } = clap::Parser::parse();
+    let read_only = name == "text_editor" && input["command"] == "view";
+    let key = input.to_string();
+    {
+        let mut cached = cached_views.lock().unwrap_or_else(|error| error.into_inner());
+        // any other call might change files, so it throws out everything cached so far
+        if !read_only {
+            cached.clear();
+        } else if let Some(output) = cached.get(&key).filter(|_| cache_tool_results) {
+            let output = format!("[cached, identical to the last time this was viewed]\n{output}");
+            return Ok((output, false));
+        }
+    }
+
+    let result = match name {
         "bash" => call_bash_tool(input).await,
-        "text_editor" => call_text_editor_tool(input).await,
-        tool => bail!("Tool {tool} not available")
+        _ => call_text_editor_tool(input).await
+    };
+    let output = match result {
+        Ok(output) => output,
+        Err(error) if error.is::<InvalidInput>() => return Ok((error.to_string(), true)),
+        Err(error) => return Err(error)
+    };
+    if read_only && cache_tool_results {
+        cached_views.lock().unwrap_or_else(|error| error.into_inner()).insert(key, output.clone());
+    }
+    Ok((output, false))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn input_error(name: &str, input: serde_json::Value) -> String {
+        let result = match name {
+            "bash" => call_bash_tool(&input).await,
+            _ => call_text_editor_tool(&input).await
+        };
+        let error = result.unwrap_err();
+        assert!(error.is::<InvalidInput>(), "{error:#} isn't a tool error");
+        error.to_string()
+    }
+
+    #[tokio::test]
+    async fn wrong_arguments_are_tool_errors() {
+        use serde_json::json;
+        let output = input_error("bash", json!("ls")).await;
+        assert_eq!(output, "The argument(s) must be fields in a JSON object");
+        let output = input_error("bash", json!({ "command": 42 })).await;
+        assert_eq!(output, "The \"command\" argument is required and must be a string");
+        let output = input_error("text_editor", json!({ "command": "view" })).await;
+        assert!(output.contains("missing field `path`"), "{output}");
+        let output = input_error("text_editor", json!({ "command": "delete", "path": "a" })).await;
+        assert_eq!(output, "delete is an invalid text_editor command");
+        let input = json!({ "command": "insert", "path": "a", "insert_line": -1, "new_str": "" });
+        let output = input_error("text_editor", input).await;
+        assert!(output.contains("invalid value: integer `-1`"), "{output}");
+        let output = input_error("text_editor", json!({ "command": "create", "path": "a" })).await;
+        assert_eq!(output, "file_text is required with the create command");
+        let input = json!({ "command": "view", "path": "a", "view_range": [0, 3] });
+        let output = input_error("text_editor", input).await;
+        assert_eq!(output, "view_range must have two positive entries");
+    }
+
+    #[tokio::test]
+    async fn made_up_tool_is_a_tool_error() {
+        let input = serde_json::json!({ "query": "rust async streams" });
+        let (output, is_error) = call_tool("web_search", &input).await.unwrap();
+        assert!(is_error);
+        assert_eq!(output, "There is no web_search tool, only bash and text_editor are available");
     }
 }
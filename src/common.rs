@@ -1,23 +1,100 @@
-use serde_json::{json, Value};
+use std::{path::{Path, PathBuf}, sync::atomic::{AtomicBool, Ordering}};
+use anyhow::{Context, Result};
+use serde_json::Value;
 use tokio::io::{self, AsyncWriteExt, AsyncBufReadExt, BufReader};
 
+#[derive(serde::Serialize)]
 pub struct Tool {
     pub name: &'static str,
-    pub description: &'static str,
-    pub input_schema: &'static str,     // TODO: change this to serde_json::Value
-}
-
-impl serde::Serialize for Tool {
-    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
-        match serde_json::from_str::<Value>(self.input_schema) {
-            Ok(input_schema) => json!({
-                "name": self.name,
-                "description": self.description,
-                "input_schema": input_schema,
-            }).serialize(serializer),
-            Err(error) => Err(serde::ser::Error::custom(error))
+    pub description: String,
+    pub input_schema: Value
+}
+
+impl Tool {
+    // the description file, when given, replaces the embedded description
+    fn new(
+        name: &'static str,
+        description: &str,
+        description_file: Option<&Path>,
+        input_schema: &str
+    ) -> Result<Tool> {
+        let description = match description_file {
+            Some(path) => std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read the {name} description file {path:?}"))?,
+            None => description.to_string()
+        };
+        let input_schema = serde_json::from_str(input_schema)
+            .with_context(|| format!("The input schema of the {name} tool isn't valid JSON"))?;
+        Ok(Tool { name, description, input_schema })
+    }
+}
+
+pub fn tools(cli: &Cli) -> Result<Vec<Tool>> {
+    let mut tools = vec![
+        Tool::new(
+            "bash",
+            include_str!("./resources/bash-description.txt"),
+            cli.bash_description_file.as_deref(),
+            include_str!("./resources/bash-schema.json")
+        )?,
+        Tool::new(
+            "text_editor",
+            include_str!("./resources/text_editor-description.txt"),
+            cli.text_editor_description_file.as_deref(),
+            include_str!("./resources/text_editor-schema.json")
+        )?
+    ];
+
+    // a lone server is implied, so the selector only shows up when there's a choice
+    if cli.servers.len() > 1 {
+        for tool in &mut tools {
+            tool.input_schema["properties"]["server"] = serde_json::json!({
+                "description": "Which server to run on, defaults to the first one.",
+                "enum": cli.servers,
+                "type": "string"
+            });
         }
     }
+    Ok(tools)
+}
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ImageSource {
+    Base64 { media_type: String, data: String }
+}
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentBlock {
+    Text { text: String },
+    Image { source: ImageSource }
+}
+
+// plain text serializes as a bare string, which is all the current tools produce
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[serde(untagged)]
+pub enum ToolOutput {
+    Text(String),
+    Blocks(Vec<ContentBlock>)
+}
+
+impl Default for ToolOutput {
+    fn default() -> Self {
+        ToolOutput::Text(String::new())
+    }
+}
+
+impl From<String> for ToolOutput {
+    fn from(text: String) -> Self {
+        ToolOutput::Text(text)
+    }
+}
+
+impl From<&str> for ToolOutput {
+    fn from(text: &str) -> Self {
+        ToolOutput::Text(text.into())
+    }
 }
 
 #[derive(Clone, Debug, Default, serde::Deserialize)]
@@ -26,7 +103,9 @@ pub struct ToolUse {
     pub id: String,
     pub input: Value,
     #[serde(default)]
-    pub output: (String, bool)      // bool denotes whether error
+    pub raw_input: Option<String>,      // set when the streamed input isn't valid JSON
+    #[serde(default)]
+    pub output: (ToolOutput, bool)      // bool denotes whether error
 }
 
 #[derive(Clone, Debug)]
@@ -35,32 +114,218 @@ pub struct Exchange {
     pub response: Vec<(String, Vec<ToolUse>)>
 }
 
-#[derive(Clone, Debug, clap::Parser, PartialEq)]
+#[derive(Clone, Debug, clap::Subcommand, PartialEq)]
+pub enum Command {
+    /// Print a completion script for the given shell
+    #[command(hide = true)]
+    Completions { shell: clap_complete::Shell }
+}
+
+#[derive(Clone, Debug, clap::Parser, PartialEq, serde::Serialize)]
 #[command(version, about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+#[command(after_help = "Exit codes with --prompt-file: 0 when the model finished answering, \
+    1 on an error such as a failed request, 2 when --exchange-timeout cut it short and 130 when \
+    Ctrl+C did.")]
 pub struct Cli {
-    #[arg(long)]
-    pub server: String,
+    #[command(subcommand)]
+    #[serde(skip)]
+    pub command: Option<Command>,
+    /// Host of a bash-agent-server, may be passed multiple times to give the model several
+    #[arg(long = "server", value_name = "HOST", required_unless_present = "no_tools")]
+    pub servers: Vec<String>,
     #[arg(long)]
     pub model: String,
+    /// File whose contents are appended to the system prompt, may be passed multiple times
+    #[arg(long = "system-prompt", value_name = "PATH")]
+    pub system_prompts: Vec<PathBuf>,
+    /// Leave the embedded default out of the system prompt
+    #[arg(long)]
+    pub no_default_system: bool,
+    /// Give up on a prompt and its tool calls after this many seconds
+    #[arg(long, value_name = "SECS")]
+    pub exchange_timeout: Option<u64>,
+    /// Show the model and turn number in the input prompt
+    #[arg(long)]
+    pub prompt_context: bool,
+    /// Chat in a full-screen interface with a scrollable conversation instead of the plain REPL
+    #[arg(long, conflicts_with_all = ["prompt_file", "show_ratelimits"])]
+    pub tui: bool,
+    /// Truncate bash output sent to the model to this many characters
+    #[arg(long, value_name = "CHARS", default_value_t = 30000)]
+    pub max_bash_output: usize,
+    /// Lines of context shown around a str_replace edit, the server's default when unset
+    #[arg(long, value_name = "LINES")]
+    pub edit_context_lines: Option<u32>,
+    /// Note on stderr that a bash command is still running every this many seconds, 0 to disable
+    #[arg(long, value_name = "SECS", default_value_t = 10)]
+    pub heartbeat_interval: u64,
+    /// Truncate file views sent to the model to this many lines
+    #[arg(long, value_name = "LINES", default_value_t = 2000)]
+    pub max_view_lines: usize,
+    /// Ask the model to continue responses cut off by max_tokens
+    #[arg(long)]
+    pub auto_continue: bool,
+    /// The most times a single response is continued with --auto-continue
+    #[arg(long, default_value_t = 4)]
+    pub max_continuations: u32,
+    /// Don't print tool calls and their output, only the model's response
+    #[arg(long)]
+    pub quiet: bool,
+    /// Only send the most recent N prior exchanges to the model
+    #[arg(long, value_name = "N")]
+    pub max_context_messages: Option<usize>,
+    /// Chat without advertising any tools, no server is needed
+    #[arg(long)]
+    pub no_tools: bool,
+    /// Send the contents of this file as the only prompt and exit once it's answered
+    #[arg(long, value_name = "PATH")]
+    pub prompt_file: Option<PathBuf>,
+    /// Start the response to --prompt-file with this text, which the model then continues
+    #[arg(long, value_name = "TEXT", requires = "prompt_file")]
+    pub prefill: Option<String>,
+    /// Wait this long between sending tool results and the next request
+    #[arg(long, value_name = "MS", default_value_t = 0)]
+    pub tool_loop_delay: u64,
+    /// Print the model, system prompt, tools and parameters that would be used, then exit
+    #[arg(long)]
+    pub print_config: bool,
+    /// Show the model the server's full debug output for tool errors rather than just the causes
+    #[arg(long)]
+    pub verbose_tool_errors: bool,
+    /// Answer repeated text_editor views from a cache until another tool call could change files
+    #[arg(long)]
+    pub cache_tool_results: bool,
+    /// Print the rate limit headers of each response to stderr
+    #[arg(long)]
+    pub show_ratelimits: bool,
+    /// Mirror everything printed to stdout into this file
+    #[arg(long, value_name = "PATH")]
+    pub output_file: Option<PathBuf>,
+    /// Append to --output-file instead of truncating it
+    #[arg(long, requires = "output_file")]
+    pub append_output: bool,
+    /// Describe the bash tool to the model with this file instead of the built-in description
+    #[arg(long, value_name = "PATH")]
+    pub bash_description_file: Option<PathBuf>,
+    /// Describe the text_editor tool to the model with this file instead of the built-in one
+    #[arg(long, value_name = "PATH")]
+    pub text_editor_description_file: Option<PathBuf>,
+    #[arg(value_parser = parse_temperature)]
     pub temperature: Option<f64>,
     pub max_tokens: Option<u32>
 }
 
+lazy_static::lazy_static! {
+    // set by the /model command, takes precedence over --model for the rest of the session
+    pub static ref model_override: std::sync::Mutex<Option<String>> = Default::default();
+}
+
+fn parse_temperature(value: &str) -> Result<f64, String> {
+    let temperature = value.parse::<f64>().map_err(|error| error.to_string())?;
+    if !(0.0..=1.0).contains(&temperature) {
+        return Err("Anthropic accepts temperatures from 0 to 1".into());
+    }
+    Ok(temperature)
+}
+
+pub fn current_model(cli: &Cli) -> String {
+    let model = model_override.lock().unwrap_or_else(|error| error.into_inner()).clone();
+    model.unwrap_or_else(|| cli.model.clone())
+}
+
+pub fn compose_system_prompt(cli: &Cli) -> Result<String> {
+    let mut parts = vec![];
+    if !cli.no_default_system {
+        parts.push(include_str!("resources/system-prompt.txt").trim_end().to_string());
+    }
+    for path in &cli.system_prompts {
+        let part = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read system prompt file {path:?}"))?;
+        parts.push(part.trim_end().to_string());
+    }
+
+    Ok(parts.join("\n\n"))
+}
+
+lazy_static::lazy_static! {
+    // streamed tokens are flushed on newlines, or soon after a partial line, not per chunk
+    static ref stdout_buffer: tokio::sync::Mutex<io::BufWriter<io::Stdout>> =
+        tokio::sync::Mutex::new(io::BufWriter::new(io::stdout()));
+}
+
+const FLUSH_INTERVAL: std::time::Duration = std::time::Duration::from_millis(50);
+// set while a flush is on its way for a partial line
+static flush_scheduled: AtomicBool = AtomicBool::new(false);
+
+lazy_static::lazy_static! {
+    static ref transcript: std::sync::Mutex<Option<std::fs::File>> = Default::default();
+}
+
+/// Starts mirroring stdout into `path`, appending to it instead of truncating if `append` is set.
+pub fn open_transcript(path: &Path, append: bool) -> Result<()> {
+    let file = std::fs::OpenOptions::new()
+        .create(true).write(true).append(append).truncate(!append)
+        .open(path)
+        .with_context(|| format!("Failed to open output file {path:?}"))?;
+    *transcript.lock().unwrap_or_else(|error| error.into_inner()) = Some(file);
+    Ok(())
+}
+
+/// Mirrors `text` into the --output-file, if there is one.
+// the file is unbuffered so every write lands right away and survives a crash
+pub fn tee(text: &[u8]) {
+    use std::io::Write;
+    let mut file = transcript.lock().unwrap_or_else(|error| error.into_inner());
+    if let Some(Err(error)) = file.as_mut().map(|file| file.write_all(text)) {
+        eprintln!("Failed to write to the output file: {error}");
+        *file = None;
+    }
+}
+
+/// Prints `text` straight to stdout, bypassing the token buffer, and mirrors it like `write`.
+pub fn echo(text: &str) {
+    print!("{text}");
+    tee(text.as_bytes());
+}
+
 pub async fn write<T: AsRef<[u8]>>(text: T) -> io::Result<()> {
-    let mut stdout = io::stdout();
+    tee(text.as_ref());
+    let mut stdout = stdout_buffer.lock().await;
     stdout.write_all(text.as_ref()).await?;
-    stdout.flush().await
+    if text.as_ref().contains(&b'\n') {
+        stdout.flush().await?;
+    } else if !stdout.buffer().is_empty() && !flush_scheduled.swap(true, Ordering::Relaxed) {
+        // keeps a partial line from sitting in the buffer while the model pauses
+        tokio::spawn(async {
+            tokio::time::sleep(FLUSH_INTERVAL).await;
+            // cleared first, so that a write landing during the flush schedules the next one
+            flush_scheduled.store(false, Ordering::Relaxed);
+            if let Err(error) = flush().await {
+                eprintln!("Failed to flush stdout: {error}");
+            }
+        });
+    }
+    Ok(())
+}
+
+pub async fn flush() -> io::Result<()> {
+    stdout_buffer.lock().await.flush().await
 }
 
 pub async fn input(prompt: &str) -> io::Result<Option<String>> {
     write(prompt).await?;
+    flush().await?;
 
     let mut stdin = BufReader::new(io::stdin());
     let mut input = String::new();
     
     match stdin.read_line(&mut input).await {
         Ok(0) => Ok(None),      // user presses ctrl d
-        Ok(_) => Ok(Some(input.trim().to_string())),
+        Ok(_) => {
+            tee(input.as_bytes());      // the terminal echoes it, the transcript wouldn't
+            Ok(Some(input.trim().to_string()))
+        },
         Err(error) => Err(error),
     }
 }
@@ -0,0 +1,80 @@
+use anyhow::Result;
+use serde_json::Value;
+use tokio::sync::mpsc::UnboundedSender;
+use crate::{common::{self, echo, Exchange}, Outcome};
+
+/// Hooks into an exchange as it runs. `on_token` gets the model's text as it streams in and
+/// `on_usage` the token counts once each response is done. `run_tool_loop` calls `on_tool_start`
+/// right before `call_tool` and `on_tool_output` once it returns, one tool use at a time in the
+/// order the model requested them. Tool uses whose input wasn't valid JSON skip both hooks, and
+/// calls that fail in a way that ends the exchange skip `on_tool_output`.
+pub trait Observer: Send + Sync {
+    fn on_token(&self, text: &str);
+    fn on_tool_start(&self, name: &str, input: &Value);
+    fn on_tool_output(&self, name: &str, output: &str, is_error: bool);
+    fn on_usage(&self, input_tokens: u64, output_tokens: u64);
+}
+
+/// Everything that happens during an exchange, in the order it happens. Tool calls run one at a
+/// time, so a `ToolResult` belongs to the `ToolCall` right before it.
+#[derive(Debug)]
+pub enum AgentEvent {
+    Token(String),
+    ToolCall { name: String, input: Value },
+    ToolResult { output: String, is_error: bool },
+    Usage { input_tokens: u64, output_tokens: u64 },
+    /// Always the last event, with the finished exchange or the error that ended it
+    Done(Result<(Exchange, Outcome)>)
+}
+
+/// Turns the hooks into events on a channel.
+pub struct ChannelObserver(pub UnboundedSender<AgentEvent>);
+
+// a closed channel only means nobody is listening anymore, which shouldn't stop the exchange
+impl Observer for ChannelObserver {
+    fn on_token(&self, text: &str) {
+        let _ = self.0.send(AgentEvent::Token(text.into()));
+    }
+
+    fn on_tool_start(&self, name: &str, input: &Value) {
+        let _ = self.0.send(AgentEvent::ToolCall { name: name.into(), input: input.clone() });
+    }
+
+    fn on_tool_output(&self, _name: &str, output: &str, is_error: bool) {
+        let _ = self.0.send(AgentEvent::ToolResult { output: output.into(), is_error });
+    }
+
+    fn on_usage(&self, input_tokens: u64, output_tokens: u64) {
+        let _ = self.0.send(AgentEvent::Usage { input_tokens, output_tokens });
+    }
+}
+
+/// How a tool call is announced, bash calls by their command and the rest by their whole input.
+pub fn describe_tool_call(name: &str, input: &Value) -> String {
+    match input["command"].as_str() {
+        Some(command) if name == "bash" => format!("Running command `{command}`\n"),
+        _ => format!("Running {name} with input {}\n",
+            serde_json::to_string_pretty(input).unwrap_or_default())
+    }
+}
+
+/// Prints an event to the console, tool calls and their output only unless `quiet` is set. Tool
+/// errors are always printed, to stderr.
+pub async fn print_event(event: &AgentEvent, quiet: bool) -> Result<()> {
+    match event {
+        AgentEvent::Token(text) => common::write(text).await?,
+        AgentEvent::ToolResult { output, is_error: true } => {
+            let output = format!("{}\n\n", output.trim());
+            common::flush().await?;
+            eprint!("{output}");
+            common::tee(output.as_bytes());
+        },
+        _ if quiet => (),
+        AgentEvent::ToolCall { name, input } => echo(&describe_tool_call(name, input)),
+        // every tool's output gets the same treatment, trimmed and followed by a blank line
+        AgentEvent::ToolResult { output, .. } if !output.trim().is_empty() =>
+            echo(&format!("{}\n\n", output.trim())),
+        _ => ()
+    }
+    Ok(())
+}
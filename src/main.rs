@@ -3,63 +3,390 @@
 mod anthropic;
 mod client;
 mod common;
+mod events;
+mod tui;
 
-use std::sync::Arc;
-use anyhow::{Error, Context, Result};
+use std::{sync::{Arc, Mutex}, time::{Duration, Instant}};
+use futures::{Stream, StreamExt};
+use tokio::sync::{mpsc, Notify};
+use anyhow::{bail, Error, Context, Result};
+use clap::FromArgMatches;
 use tonic::{Status, Code::Unknown};
 use anthropic::{send_request, stream_response};
-use common::{Cli, Exchange};
+use common::{Cli, Exchange, ToolUse};
+use events::{AgentEvent, ChannelObserver, Observer};
 
-async fn run_exchange(prompt: String, exchanges: &[Exchange]) -> Result<Exchange> {
-    let mut exchange = Exchange { prompt, response: vec![] };
-    let response = send_request(exchanges, &exchange).await?;
-    let mut response = stream_response(response).await?;
+async fn request_response(exchange: &Exchange, exchanges: &[Exchange], observer: &dyn Observer)
+-> Result<(String, Vec<ToolUse>)> {
+    let Cli { auto_continue, max_continuations, prefill, .. } = clap::Parser::parse();
+    // the prefill opens the first response of the exchange as a trailing assistant turn
+    let prefill = match prefill {
+        Some(prefill) if exchange.response.is_empty() => prefill.trim_end().to_string(),
+        _ => String::new()
+    };
+    let mut prefilled = exchange.clone();
+    if !prefill.is_empty() {
+        prefilled.response.push((prefill.clone(), vec![]));
+        observer.on_token(&prefill);
+    }
+
+    let response = send_request(exchanges, &prefilled).await?;
+    let (mut message, mut tool_uses, mut stop_reason) = stream_response(response, observer).await?;
+    message.insert_str(0, &prefill);
+
+    // resend a response cut off by max_tokens as a trailing assistant turn to have it continued
+    for _ in 0..max_continuations {
+        let truncated = stop_reason.as_deref() == Some("max_tokens");
+        if !auto_continue || !truncated || !tool_uses.is_empty() || message.trim().is_empty() {
+            break;
+        }
+
+        message.truncate(message.trim_end().len());     // the API rejects trailing whitespace
+        let mut partial = exchange.clone();
+        partial.response.push((message.clone(), vec![]));
+        let response = send_request(exchanges, &partial).await?;
+        let continuation;
+        (continuation, tool_uses, stop_reason) = stream_response(response, observer).await?;
+        message.push_str(&continuation);
+    }
 
-    while !response.1.is_empty() {
+    if !message.is_empty() {
+        observer.on_token("\n\n");
+    }
+    Ok((message, tool_uses))
+}
+
+async fn run_tool_loop(exchange: &mut Exchange, exchanges: &[Exchange], observer: &dyn Observer)
+-> Result<()> {
+    let mut response = request_response(exchange, exchanges, observer).await?;
+
+    let Cli { no_tools, tool_loop_delay, verbose_tool_errors, .. } = clap::Parser::parse();
+    while !no_tools && !response.1.is_empty() {
         for tool_use in response.1.as_mut_slice() {
+            if tool_use.raw_input.is_some() {
+                continue;       // output already holds the parse error
+            }
+            observer.on_tool_start(&tool_use.name, &tool_use.input);
             let result = client::call_tool(&tool_use.name, &tool_use.input).await;
-            tool_use.output = match result.map_err(Error::downcast::<Status>) {
-                Ok(output) => (output, false),
-                Err(Ok(error)) if error.code() == Unknown => (error.message().into(), true),
+            let (output, is_error) = match result.map_err(Error::downcast::<Status>) {
+                Ok(result) => result,
+                Err(Ok(error)) if error.code() == Unknown && verbose_tool_errors
+                    && !error.details().is_empty() =>
+                    (String::from_utf8_lossy(error.details()).into_owned(), true),
+                Err(Ok(error)) if error.code() == Unknown => (error.message().to_string(), true),
                 Err(Ok(error)) => return Err(error.into()),
-                Err(Err(error)) => return Err(error.into())
+                Err(Err(error)) => return Err(error)
             };
+            observer.on_tool_output(&tool_use.name, &output, is_error);
+            tool_use.output = (output.into(), is_error);
         }
         exchange.response.push(response.clone());
-        response = stream_response(send_request(exchanges, &exchange).await?).await?;
+        if tool_loop_delay > 0 {
+            // cancellation drops this future, so the delay never holds up Ctrl+C
+            tokio::time::sleep(Duration::from_millis(tool_loop_delay)).await;
+        }
+        response = request_response(exchange, exchanges, observer).await?;
     }
 
     exchange.response.push(response);
-    Ok(exchange)
+    Ok(())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Outcome {
+    Completed,
+    Cancelled,
+    TimedOut
+}
+
+impl Outcome {
+    // keep in sync with the exit codes documented on Cli
+    fn exit_code(self) -> i32 {
+        match self {
+            Outcome::Completed => 0,
+            Outcome::TimedOut => 2,
+            Outcome::Cancelled => 130
+        }
+    }
+}
+
+/// Runs `exchange` until the model stops calling tools or `cancel` is notified. A cancelled run
+/// returns `Outcome::Cancelled` with `exchange.response` holding only the rounds whose tool calls
+/// all finished, the round in flight (partially streamed text and any pending tool calls) is
+/// discarded.
+async fn run_exchange(
+    exchange: &mut Exchange,
+    exchanges: &[Exchange],
+    observer: &dyn Observer,
+    cancel: &Notify
+) -> Result<Outcome> {
+    tokio::select! {
+        _ = cancel.notified() => Ok(Outcome::Cancelled),
+        result = run_tool_loop(exchange, exchanges, observer) => result.map(|_| Outcome::Completed)
+    }
+}
+
+// token counts the API reported for the latest response
+static last_usage: Mutex<Option<(u64, u64)>> = Mutex::new(None);
+
+fn record_usage(input_tokens: u64, output_tokens: u64) {
+    *last_usage.lock().unwrap_or_else(|error| error.into_inner()) =
+        Some((input_tokens, output_tokens));
+}
+
+// all current Anthropic models share this context window
+const CONTEXT_WINDOW: usize = 200_000;
+
+// a rough rule of thumb of 4 bytes per token for English text and code, not a real tokenizer
+fn estimate_tokens(value: &serde_json::Value) -> usize {
+    match value {
+        serde_json::Value::Null => 0,
+        serde_json::Value::Array(values) if values.is_empty() => 0,
+        serde_json::Value::String(text) => text.len().div_ceil(4),
+        value => value.to_string().len().div_ceil(4)
+    }
+}
+
+fn token_summary(exchanges: &[Exchange]) -> Result<String> {
+    let next = Exchange { prompt: "".into(), response: vec![] };
+    let mut body = anthropic::build_request_body(exchanges, &next)?;
+    if let Some(messages) = body["messages"].as_array_mut() {
+        messages.pop();     // the placeholder for the next prompt
+    }
+    let system = estimate_tokens(&body["system"]);
+    let history = estimate_tokens(&body["messages"]);
+    let tools = estimate_tokens(&body["tools"]);
+    let mut summary = format!("About {} of {CONTEXT_WINDOW} tokens (system ~{system}, \
+        history ~{history}, tools ~{tools}), estimated from the text length rather than counted.",
+        system + history + tools);
+    if let Some((input, output)) = *last_usage.lock().unwrap_or_else(|error| error.into_inner()) {
+        summary += &format!(" The last response took {input} input and {output} output tokens.");
+    }
+    Ok(summary)
+}
+
+// returns what the command printed, or None when the input isn't a known command and should be
+// sent as a prompt
+async fn run_command(input: &str, exchanges: &mut Vec<Exchange>) -> Result<Option<String>> {
+    let (command, argument) = input.split_once(' ').unwrap_or((input, ""));
+    Ok(Some(match command {
+        "/dump-request" => {
+            let next = Exchange { prompt: argument.trim().into(), response: vec![] };
+            let body = anthropic::build_request_body(exchanges, &next)?;
+            format!("{}\n", serde_json::to_string_pretty(&body)?)
+        },
+        // the history is provider neutral so it carries over to the new model as is
+        "/model" if argument.trim().is_empty() => {
+            format!("Using {}.\n", common::current_model(&clap::Parser::parse()))
+        },
+        "/model" => {
+            *common::model_override.lock().unwrap_or_else(|error| error.into_inner()) =
+                Some(argument.trim().into());
+            format!("Switched to {}.\n", argument.trim())
+        },
+        "/stats" => format!("{}\n", client::edit_summary()),
+        "/tokens" => format!("{}\n", token_summary(exchanges)?),
+        "/save-output" if argument.trim().is_empty() => "Usage: /save-output <path>\n".into(),
+        "/save-output" => {
+            let path = argument.trim();
+            match client::latest_output().map(|output| std::fs::write(path, output)) {
+                Some(Ok(())) => format!("Saved the last tool output to {path}.\n"),
+                Some(Err(error)) => format!("Failed to save to {path}: {error}\n"),
+                None => "No tool has produced output yet.\n".to_string()
+            }
+        },
+        // files on disk are never touched, only what the model and the servers remember
+        "/reset" if !["", "--server"].contains(&argument.trim()) => {
+            "Usage: /reset [--server]\n".into()
+        },
+        "/reset" => {
+            let reset_server = argument.trim() == "--server";
+            if reset_server {
+                if let Err(error) = client::reset_servers().await {
+                    return Ok(Some(format!("Failed to reset the servers: {error:#}\n")));
+                }
+            }
+            exchanges.clear();
+            if reset_server {
+                "Started a new conversation with fresh shells and no edit history.\n".into()
+            } else {
+                "Started a new conversation, the shell and edit history are as they were.\n".into()
+            }
+        },
+        _ => return Ok(None)
+    }))
 }
 
-async fn trigger_cancel(cancel: Arc<tokio::sync::Notify>) {
+/// Runs an exchange for `prompt` in the background and streams what happens, ending with
+/// `AgentEvent::Done` once it completes, fails, times out or `cancel` is notified. The channel is
+/// unbounded, so the exchange never waits on a slow reader and its events queue up in memory
+/// instead. Dropping the stream doesn't stop the exchange, notify `cancel` for that.
+fn stream_prompt(prompt: String, exchanges: Vec<Exchange>, cancel: Arc<Notify>)
+-> impl Stream<Item = AgentEvent> {
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let observer = ChannelObserver(sender.clone());
+        let Cli { exchange_timeout, .. } = clap::Parser::parse();
+        let timeout = async {
+            match exchange_timeout {
+                Some(secs) => tokio::time::sleep(Duration::from_secs(secs)).await,
+                None => std::future::pending().await
+            }
+        };
+
+        let mut exchange = Exchange { prompt, response: vec![] };
+        let result = tokio::select! {
+            _ = timeout => Ok(Outcome::TimedOut),
+            result = run_exchange(&mut exchange, &exchanges, &observer, &cancel) => result
+        };
+        let _ = sender.send(AgentEvent::Done(result.map(|outcome| (exchange, outcome))));
+    });
+    futures::stream::poll_fn(move |context| receiver.poll_recv(context))
+}
+
+async fn run_prompt(prompt: String, exchanges: &[Exchange], cancel: &Arc<Notify>)
+-> Result<(Exchange, Outcome)> {
+    let Cli { quiet, .. } = clap::Parser::parse();
+    let mut events = std::pin::pin!(stream_prompt(prompt, exchanges.to_vec(), Arc::clone(cancel)));
+    while let Some(event) = events.next().await {
+        match event {
+            AgentEvent::Usage { input_tokens, output_tokens } =>
+                record_usage(input_tokens, output_tokens),
+            AgentEvent::Done(result) => {
+                let (exchange, outcome) = result?;
+                if outcome == Outcome::TimedOut {
+                    common::flush().await?;
+                    common::echo("\nExchange timed out.\n");
+                }
+                return Ok((exchange, outcome));
+            },
+            event => events::print_event(&event, quiet).await?
+        }
+    }
+    bail!("The exchange stopped without finishing")
+}
+
+// a second Ctrl+C within this long of the first asks to exit rather than cancel
+const DOUBLE_PRESS_INTERVAL: Duration = Duration::from_millis(500);
+
+// every Ctrl+C notifies `cancel`, a rapid second press also notifies `exit`
+async fn trigger_cancel(cancel: Arc<Notify>, exit: Arc<Notify>) {
+    let mut last_press: Option<Instant> = None;
     loop {
         if let Err(error) = tokio::signal::ctrl_c().await {
             eprintln!("Failed to listen for Ctrl+C: {error}");
             break;
         }
         cancel.notify_waiters();
+
+        let now = Instant::now();
+        if last_press.is_some_and(|last_press| now - last_press < DOUBLE_PRESS_INTERVAL) {
+            exit.notify_one();
+        }
+        last_press = Some(now);
     }
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let _: Cli = clap::Parser::parse();
+    // subcommands skip the required REPL arguments, so handle them before building a Cli
+    let mut command = <Cli as clap::CommandFactory>::command();
+    let matches = command.clone().get_matches();
+    if matches.subcommand().is_some() {
+        let common::Command::Completions { shell } = FromArgMatches::from_arg_matches(&matches)?;
+        clap_complete::generate(shell, &mut command, "bash-agent", &mut std::io::stdout());
+        return Ok(());
+    }
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|error| error.exit());
+    // unreadable prompt or description files fail here rather than at the first request
+    common::compose_system_prompt(&cli)?;
+    common::tools(&cli)?;
+    if cli.print_config {
+        // read back from a request body so the defaults shown are the ones actually sent, and the
+        // API key only ever comes from the environment, so none of this is secret
+        let next = Exchange { prompt: "".into(), response: vec![] };
+        let body = anthropic::build_request_body(&[], &next)?;
+        let mut parameters = serde_json::to_value(&cli)?;
+        for key in ["temperature", "max_tokens"] {
+            parameters[key] = body[key].clone();
+        }
+        let config = serde_json::json!({
+            "provider": "anthropic",
+            "model": body["model"],
+            "system": body["system"],
+            "tools": body.get("tools").cloned().unwrap_or_else(|| serde_json::json!([])),
+            "parameters": parameters
+        });
+        println!("{}", serde_json::to_string_pretty(&config)?);
+        return Ok(());
+    }
+    if let Some(path) = &cli.output_file {
+        common::open_transcript(path, cli.append_output)?;
+    }
 
-    let cancel = Arc::new(tokio::sync::Notify::new());
-    tokio::spawn(trigger_cancel(Arc::clone(&cancel)));
+    let cancel = Arc::new(Notify::new());
+    let exit = Arc::new(Notify::new());
+    tokio::spawn(trigger_cancel(Arc::clone(&cancel), Arc::clone(&exit)));
+    tokio::spawn(async move {
+        exit.notified().await;
+        let _ = common::flush().await;
+        common::echo("\n");
+        std::process::exit(Outcome::Cancelled.exit_code());
+    });
+
+    if let Some(path) = &cli.prompt_file {
+        let prompt = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read prompt file {path:?}"))?;
+        if prompt.trim().is_empty() {
+            bail!("Prompt file {path:?} is empty");
+        }
+        let (_, outcome) = run_prompt(prompt, &[], &cancel).await?;
+        common::flush().await?;
+        std::process::exit(outcome.exit_code());
+    }
+
+    if cli.tui {
+        return tui::run(&cancel).await;
+    }
 
     let mut exchanges = vec![];
     loop {
-        let Some(prompt) = common::input("> ").await.context("Failed to read prompt")? else {
-            println!();
+        let indicator = if cli.prompt_context {
+            format!("{}[turn {}]> ", common::current_model(&cli), exchanges.len() + 1)
+        } else {
+            "> ".to_string()
+        };
+        let Some(prompt) = common::input(&indicator).await.context("Failed to read prompt")? else {
+            common::echo("\n");
             break;
         };
+        if prompt.is_empty() {
+            continue;
+        }
+        // a command that fails, e.g. /tokens with a missing description file, changes nothing
+        match run_command(&prompt, &mut exchanges).await {
+            Ok(Some(output)) => {
+                common::echo(&output);
+                continue;
+            },
+            Ok(None) => (),
+            Err(error) => {
+                eprintln!("Error: {error:#}");
+                continue;
+            }
+        }
 
-        tokio::select! {
-            _ = cancel.notified() => continue,
-            exchange = run_exchange(prompt, &exchanges) => exchanges.push(exchange?)
+        // a failed request, e.g. after a typo in /model, leaves the conversation as it was
+        let exchange = match run_prompt(prompt, &exchanges, &cancel).await {
+            Ok((exchange, _)) => exchange,
+            Err(error) => {
+                common::flush().await?;
+                eprintln!("\nError: {error:#}");
+                continue;
+            }
+        };
+        if !exchange.response.is_empty() {
+            exchanges.push(exchange);
         }
     }
 
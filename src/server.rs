@@ -1,12 +1,12 @@
-#![feature(iter_advance_by)]
-
 use std::{collections::HashMap, os::fd::AsRawFd, path::{Path, PathBuf}, process::Stdio};
+use std::{future::Future, sync::{atomic::{AtomicU32, Ordering}, Arc}, time::Duration};
 use anyhow::{bail, Context, Result};
-use tonic::{transport::Server, Request, Response, Status};
+use tonic::{transport::Server, Code, Request, Response, Status};
 use tokio::{io::{AsyncReadExt, AsyncWriteExt}, fs, process::{Child, Command}, sync::Mutex};
-use nix::fcntl::{fcntl, FcntlArg::{F_GETFL, F_SETFL}, OFlag};
+use tokio::time::Instant;
+use nix::{fcntl::{fcntl, FcntlArg::{F_GETFL, F_SETFL}, OFlag}, sys::signal, unistd::Pid};
 use bash_agent::{
-    tool_runner_server, BashRequest, BashResponse, CreateRequest, InsertRequest,
+    tool_runner_server, BashRequest, BashResponse, CreateRequest, CreateResponse, InsertRequest,
     Snippet, StringReplaceRequest, UndoEditRequest, ViewRange, ViewRequest
 };
 
@@ -14,16 +14,22 @@ mod bash_agent {
     tonic::include_proto!("bash_agent");
     impl Snippet {
         pub fn new(content: &str, range: Option<(usize, usize)>) -> Snippet {
+            Snippet::with_padding(content, range, 4)
+        }
+
+        // `padding` lines of context are kept on either side of the range
+        pub fn with_padding(content: &str, range: Option<(usize, usize)>, padding: usize)
+        -> Snippet {
             let lines = content.split("\n").map(str::to_owned);
             let Some((mut start, end)) = range else {
-                return Snippet { start: 1, lines: lines.collect() };
+                return Snippet { start: 1, lines: lines.collect(), ..Default::default() };
             };
     
-            let padding = 4;
             start = start.saturating_sub(padding);
             Snippet {
-                start: 1 + start as u32,
-                lines: lines.take(end + padding).skip(start).collect()
+                start: (start as u64).saturating_add(1),
+                lines: lines.take(end.saturating_add(padding)).skip(start).collect(),
+                ..Default::default()
             }
         }
     }    
@@ -47,46 +53,111 @@ fn read_pipe<T: AsRawFd>(pipe: &mut T) -> Result<String> {
     Ok(output)
 }
 
-async fn run_bash_tool(bash: &mut Child, request: BashRequest) -> Result<BashResponse> {
-    let stdin = bash.stdin.as_mut().context("Failed to get stdin handle.")?;
-    let fd = stdin.as_raw_fd();
-    let mut handle = tokio::task::spawn_blocking(move || unsafe { ioc_pipe_wait_read_invoc(fd) });
-
-    stdin.write_all((request.input + "\n").as_bytes()).await?;
-    stdin.flush().await?;
+// a shell that exited stays dead until the server is reset or restarted
+fn check_alive(bash: &mut Child) -> Result<()> {
+    if let Some(status) = bash.try_wait().context("Failed to check on bash")? {
+        bail!("The shell exited with {status}, reset the server or restart it for a new one");
+    }
+    Ok(())
+}
 
-    let stdout = bash.stdout.as_mut().context("Failed to get stdout handle.")?;
-    let mut stdout_bufreader = tokio::io::BufReader::new(stdout);
+// bash is only checked on this often, for when it exits but something it left running in the
+// background holds on to its pipes, so they never close
+const LIVENESS_INTERVAL: Duration = Duration::from_secs(1);
+// what a dead shell printed last goes in the error, but no more of it than this
+const DEAD_SHELL_OUTPUT: usize = 2000;
+
+// reads everything bash prints until `finished` resolves. with an idle timeout, a command that goes
+// quiet for that long without finishing is assumed to be running on in the background, e.g. a
+// daemon, and what it printed so far is returned rather than waiting on it forever
+async fn collect_output(
+    bash: &mut Child,
+    finished: impl Future<Output = Result<()>>,
+    idle_timeout: Option<Duration>
+) -> Result<String> {
+    tokio::pin!(finished);
+    let mut liveness = tokio::time::interval(LIVENESS_INTERVAL);
     let mut stdout_buffer = [0u8; 1024];
-
-    let stderr = bash.stderr.as_mut().ok_or(Status::internal("Failed to get stderr handle."))?;
-    let mut stderr_bufreader = tokio::io::BufReader::new(stderr);
     let mut stderr_buffer = [0u8; 1024];
-
     let mut output = String::new();
-    loop {
+    let mut last_output = Instant::now();
+    let mut idle = false;
+    let exited = loop {
+        let stdout = bash.stdout.as_mut().context("Failed to get stdout handle.")?;
+        let stderr = bash.stderr.as_mut().context("Failed to get stderr handle.")?;
+        let idle_deadline = last_output + idle_timeout.unwrap_or_default();
         tokio::select! {
-            result = &mut handle => {
-                result.context("Failed to wait for ioctl")?.context("Error calling ioctl")?;
-                break;
+            result = &mut finished => {
+                result?;
+                break false;
+            },
+            _ = tokio::time::sleep_until(idle_deadline), if idle_timeout.is_some() => {
+                idle = true;
+                break false;
             },
-            n = stdout_bufreader.read(&mut stdout_buffer) => match n {
-                Ok(0) => break,
-                Ok(n) => output.push_str(&String::from_utf8_lossy(&stdout_buffer[..n])),
-                Err(error) => return Err(error.into())
+            _ = liveness.tick() => {
+                if bash.try_wait().context("Failed to check on bash")?.is_some() {
+                    break true;
+                }
             },
-            n = stderr_bufreader.read(&mut stderr_buffer) => match n {
-                Ok(0) => break,
-                Ok(n) => output.push_str(&String::from_utf8_lossy(&stderr_buffer[..n])),
-                Err(error) => return Err(error.into())
+            n = stdout.read(&mut stdout_buffer) => match n? {
+                0 => break true,
+                n => {
+                    output.push_str(&String::from_utf8_lossy(&stdout_buffer[..n]));
+                    last_output = Instant::now();
+                }
+            },
+            n = stderr.read(&mut stderr_buffer) => match n? {
+                0 => break true,
+                n => {
+                    output.push_str(&String::from_utf8_lossy(&stderr_buffer[..n]));
+                    last_output = Instant::now();
+                }
             }
         }
+    };
+
+    output.push_str(&read_pipe(bash.stdout.as_mut().context("Failed to get stdout handle.")?)?);
+    output.push_str(&read_pipe(bash.stderr.as_mut().context("Failed to get stderr handle.")?)?);
+
+    if exited {
+        let status = match tokio::time::timeout(Duration::from_secs(1), bash.wait()).await {
+            Ok(status) => status.context("Failed to wait for bash")?.to_string(),
+            Err(_) => "an unknown status".to_string()
+        };
+        let skipped = output.chars().count().saturating_sub(DEAD_SHELL_OUTPUT);
+        let mut tail = output.chars().skip(skipped).collect::<String>();
+        if skipped > 0 {
+            tail = format!("[... {skipped} characters truncated ...]\n{tail}");
+        }
+        bail!("The shell exited with {status}, reset the server or restart it for a new one. \
+            Its last output was:\n{tail}");
     }
 
-    output.push_str(&read_pipe(stdout_bufreader.into_inner())?);
-    output.push_str(&read_pipe(stderr_bufreader.into_inner())?);
+    if idle {
+        output.push_str("\n[the command appears to be still running in the background]");
+    }
+    Ok(output)
+}
+
+async fn run_bash_tool(bash: &mut Child, request: BashRequest, idle_timeout: Option<Duration>)
+-> Result<BashResponse> {
+    check_alive(bash)?;
+    let stdin = bash.stdin.as_mut().context("Failed to get stdin handle.")?;
+    let fd = stdin.as_raw_fd();
+    let handle = tokio::task::spawn_blocking(move || unsafe { ioc_pipe_wait_read_invoc(fd) });
+    let start = std::time::Instant::now();
+
+    stdin.write_all((request.input + "\n").as_bytes()).await?;
+    stdin.flush().await?;
 
-    Ok(BashResponse { output })
+    // the ioctl returns once bash is back to reading its next input
+    let finished = async {
+        handle.await.context("Failed to wait for ioctl")?.context("Error calling ioctl")?;
+        Ok(())
+    };
+    let output = collect_output(bash, finished, idle_timeout).await?;
+    Ok(BashResponse { output, elapsed_ms: Some(start.elapsed().as_millis() as u64) })
 }
 
 #[derive(Default)]
@@ -127,67 +198,127 @@ async fn view(ViewRequest { path, view_range }: ViewRequest) -> Result<Snippet>
     let Some(ViewRange { start, end }) = view_range else {
         return Ok(Snippet::new(&content, None));
     };
+    let lines = content.matches('\n').count() + 1;
+    let end = end.map_or(lines, |end| end as usize);
+    if start as usize > lines {
+        bail!("view_range starts at line {start} but {path:?} only has {lines} lines");
+    }
+    if end < start as usize {
+        bail!("view_range ends at line {end}, before it starts at line {start}");
+    }
     let start = (start as usize).saturating_sub(1);
-    let end = match end {
-        Some(end) => end as usize,
-        None => content.matches('\n').count() + 1
-    };
     Ok(Snippet::new(&content, Some((start, end))))
 }
 
-async fn create(CreateRequest { path, file_text }: CreateRequest) -> Result<()> {
+async fn create(CreateRequest { path, file_text, overwrite }: CreateRequest)
+-> Result<CreateResponse> {
     let path = validate_path(&path).await?;
-    if path.exists() {
-        bail!("File already exists");
-    }
+    let (lines_added, lines_removed) = if path.exists() && !overwrite {
+        bail!("File already exists, set overwrite to replace it");
+    } else if path.exists() {
+        let existing = fs::read_to_string(&path).await?;
+        let delta = line_delta(&existing, &file_text);
+        // write only keeps what it replaces once the file has history, so start it here for undo
+        file_history.lock().await.entry(path.clone())
+            .or_insert_with(|| FileHistoryEntry { latest: existing, history: vec![] });
+        delta
+    } else {
+        (file_text.lines().count() as u64, 0)
+    };
 
-    write(path, file_text).await
+    write(path, file_text).await?;
+    Ok(CreateResponse { lines_added, lines_removed })
 }
 
 async fn string_replace(request: StringReplaceRequest) -> Result<Snippet> {
     let path = validate_path(&request.path).await?;
-    let mut content = fs::read_to_string(&path).await?;
+    let original = fs::read_to_string(&path).await?;
+    let mut content = original.clone();
 
     let to_replace = &request.to_replace;
     let Some(index) = content.find(to_replace) else {
         bail!("No match found to `to_replace` for replacement");
     };
-    if content.matches(to_replace).skip(1).next().is_some() {
+    if content.matches(to_replace).nth(1).is_some() {
         bail!("Multiple matches found to `to_replace`, a unique match is necessary");
     }
 
-    let replacement = request.replacement.as_ref().map(String::as_str).unwrap_or("");
+    let replacement = request.replacement.as_deref().unwrap_or("");
     content.replace_range(index..index + to_replace.len(), replacement);
 
     let start = content[..index].matches('\n').count();
     let end = start + replacement.matches('\n').count();
-    let snippet = Snippet::new(&content, Some((start, end + 1)));
+    let range = Some((start, end + 1));
+    let mut snippet = match request.context_lines {
+        Some(context_lines) => Snippet::with_padding(&content, range, context_lines as usize),
+        None => Snippet::new(&content, range)
+    };
+    (snippet.lines_added, snippet.lines_removed) = line_delta(&original, &content);
 
-    write(path, content).await?;
+    if !request.preview {
+        write(path, content).await?;
+    }
     Ok(snippet)
 }
 
+// puts `text` after line `after` of `content`, or at the top for line 0, and returns the new
+// content along with a snippet of the inserted lines
+fn insert_lines(content: &str, after: usize, text: &str, path: &Path) -> Result<(String, Snippet)> {
+    // a trailing newline would otherwise leave an extra blank line behind the inserted ones
+    let text = text.strip_suffix('\n').unwrap_or(text);
+    let added = text.matches('\n').count() + 1;
+    let mut content = content.to_string();
+    if after == 0 {
+        content.insert_str(0, &format!("{text}\n"));
+    } else {
+        // byte index of the end of line `after`, i.e. its newline or the end of the last line
+        let newlines = content.matches('\n').count();
+        let index = match content.match_indices('\n').nth(after - 1) {
+            Some((index, _)) => index,
+            None if newlines == after - 1 => content.len(),
+            None => bail!("There are only {} lines in {path:?}", newlines + 1)
+        };
+        content.insert_str(index, &format!("\n{text}"));
+    }
+
+    // the new lines are after + 1..=after + added, shown along with the line they follow
+    let mut snippet = Snippet::new(&content, Some((after.saturating_sub(1), after + added)));
+    snippet.changed_start = after as u64 + 1;
+    snippet.changed_end = (after + added) as u64 + 1;
+    snippet.lines_added = added as u64;
+    Ok((content, snippet))
+}
+
 async fn insert(request: InsertRequest) -> Result<Snippet> {
     let path = validate_path(&request.path).await?;
-    let mut content = fs::read_to_string(&path).await?;
-
-    // iterate to current line
-    let line_number = request.line_number.saturating_sub(1) as usize;
-    let mut newlines = content.chars().enumerate().filter(|(_, char)| char == &'\n');
-    if newlines.advance_by(line_number).is_err() {
-        bail!("There are only {} lines in {path:?}", content.matches('\n').count());
-    };
+    let content = fs::read_to_string(&path).await?;
+    let after = request.line_number as usize;
+    let (content, snippet) = insert_lines(&content, after, &request.line, &path)?;
 
-    // insert after current line
-    let index = newlines.next().map(|(index, _)| index).unwrap_or(content.len());
-    let line = "\n".to_string() + &request.line;
-    content.insert_str(index, &line);
+    if !request.preview {
+        write(path, content).await?;
+    }
+    Ok(snippet)
+}
 
-    let end = line_number + line.matches('\n').count();
-    let snippet = Snippet::new(&content, Some((line_number, end + 1)));
+// files this short are shown whole after an undo rather than just around the changed lines
+const SMALL_FILE_LINES: usize = 40;
+
+// the range of lines in `after` that differ from `before`, excluding the common prefix and suffix
+fn changed_lines(before: &str, after: &str) -> (usize, usize) {
+    let before = before.split('\n').collect::<Vec<_>>();
+    let after = after.split('\n').collect::<Vec<_>>();
+    let prefix = before.iter().zip(&after).take_while(|(a, b)| a == b).count();
+    let suffix = before[prefix..].iter().rev().zip(after[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b).count();
+    (prefix, after.len() - suffix)
+}
 
-    write(path, content).await?;
-    Ok(snippet)
+// how many lines were added and removed going from `before` to `after`
+fn line_delta(before: &str, after: &str) -> (u64, u64) {
+    let (start, end) = changed_lines(before, after);
+    let (_, before_end) = changed_lines(after, before);
+    ((end - start) as u64, (before_end - start) as u64)
 }
 
 async fn undo_edit(request: UndoEditRequest) -> Result<Snippet> {
@@ -204,32 +335,64 @@ async fn undo_edit(request: UndoEditRequest) -> Result<Snippet> {
         return Err(error.into());
     }
 
+    let (start, end) = changed_lines(latest, &new_latest);
+    let (lines_added, lines_removed) = line_delta(latest, &new_latest);
     *latest = new_latest;
-    Ok(Snippet::new(latest, None))
+    let range = (latest.matches('\n').count() >= SMALL_FILE_LINES).then_some((start, end));
+    let mut snippet = Snippet::new(latest, range);
+    snippet.changed_start = start as u64 + 1;
+    snippet.changed_end = end as u64 + 1;
+    (snippet.lines_added, snippet.lines_removed) = (lines_added, lines_removed);
+    Ok(snippet)
+}
+
+// the whole process group goes, so nothing the old shell started in the background is left running
+async fn reset(bash: &mut Child) -> Result<()> {
+    if let Some(pid) = bash.id() {
+        kill_shell(pid)?;
+        bash.wait().await.context("Failed to wait for bash")?;
+    }
+    *bash = spawn_bash()?;
+    file_history.lock().await.clear();
+    Ok(())
 }
 
 struct ToolRunner {
-    bash: Mutex<Child>
+    bash: Mutex<Child>,
+    shell_pid: AtomicU32,       // readable while a command holds the lock on bash
+    echo_commands: bool,
+    idle_timeout: Option<Duration>
 }
 
+// the model gets the chain of causes by default, the debug output with any backtrace goes in the
+// details for clients that ask for it
 fn to_status(error: anyhow::Error) -> Status {
-    Status::unknown(format!("{error:?}"))
+    let message = match error.downcast_ref::<Status>() {
+        Some(status) => status.message().to_string(),
+        None => format!("{error:#}")
+    };
+    Status::with_details(Code::Unknown, message, format!("{error:?}").into())
 }
 
 type TonicResult<T> = Result<Response<T>, Status>;
 #[tonic::async_trait]
 impl tool_runner_server::ToolRunner for ToolRunner {
     async fn run_bash_tool(&self, request: Request<BashRequest>) -> TonicResult<BashResponse> {
+        if self.echo_commands {
+            let output = format!("[dry-run] would execute: {}", request.into_inner().input);
+            return Ok(Response::new(BashResponse { output, elapsed_ms: None }));
+        }
+
         let mut bash = self.bash.lock().await;
-        run_bash_tool(&mut bash, request.into_inner()).await.map(Response::new)
-            .map_err(|error| Status::internal(format!("{error:?}")))
+        run_bash_tool(&mut bash, request.into_inner(), self.idle_timeout).await.map(Response::new)
+            .map_err(to_status)
     }
 
     async fn view(&self, request: Request<ViewRequest>) -> TonicResult<Snippet> {
         view(request.into_inner()).await.map(Response::new).map_err(to_status)
     }
 
-    async fn create(&self, request: Request<CreateRequest>) -> TonicResult<()> {
+    async fn create(&self, request: Request<CreateRequest>) -> TonicResult<CreateResponse> {
         create(request.into_inner()).await.map(Response::new).map_err(to_status)
     }
 
@@ -244,6 +407,13 @@ impl tool_runner_server::ToolRunner for ToolRunner {
     async fn undo_edit(&self, request: Request<UndoEditRequest>) -> TonicResult<Snippet> {
         undo_edit(request.into_inner()).await.map(Response::new).map_err(to_status)
     }
+
+    async fn reset(&self, _: Request<()>) -> TonicResult<()> {
+        let mut bash = self.bash.lock().await;
+        reset(&mut bash).await.map_err(to_status)?;
+        self.shell_pid.store(bash.id().unwrap_or_default(), Ordering::Relaxed);
+        Ok(Response::new(()))
+    }
 }
 
 fn set_nonblocking<T: AsRawFd>(pipe: &mut T) -> Result<i32> {
@@ -252,9 +422,12 @@ fn set_nonblocking<T: AsRawFd>(pipe: &mut T) -> Result<i32> {
     fcntl(pipe.as_raw_fd(), F_SETFL(flags)).map_err(Into::into)
 }
 
+// in a process group of its own the terminal's Ctrl+C doesn't reach bash, which has to outlive
+// the server long enough for its state to be saved
 fn spawn_bash() -> Result<Child> {
     let mut bash = Command::new("bash")
         .stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped())
+        .process_group(0)
         .spawn().context("Error spawning bash")?;
 
     let stdout = bash.stdout.as_mut().ok_or(Status::internal("Failed to get stdout handle."))?;
@@ -266,10 +439,289 @@ fn spawn_bash() -> Result<Child> {
     Ok(bash)
 }
 
+// takes down everything bash started along with it, as the terminal's Ctrl+C would have
+fn kill_shell(pid: u32) -> Result<()> {
+    if pid == 0 {
+        return Ok(());      // already reaped, and group 0 would be the server's own
+    }
+    match signal::killpg(Pid::from_raw(pid as i32), signal::SIGKILL) {
+        Ok(()) | Err(nix::errno::Errno::ESRCH) => Ok(()),
+        Err(error) => Err(error).context("Failed to kill the shell")
+    }
+}
+
+fn shell_quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', r"'\''"))
+}
+
+// the state file is a bash script that cds into the old cwd and redeclares the saved variables.
+// whatever is reading the shell's stdin, e.g. a python REPL the model left open, gets the command
+// instead of bash, so only lines in the form bash would print make it into the file
+async fn save_shell_state(bash: &mut Child, path: &Path, env: &[String]) -> Result<()> {
+    let pid = bash.id().context("The shell already exited")?;
+    let cwd = fs::read_link(format!("/proc/{pid}/cwd")).await.context("Failed to read the cwd")?;
+    let mut state = format!("cd {}\n", shell_quote(&cwd.to_string_lossy()));
+    if !env.is_empty() {
+        // %q keeps each value on one line, even one with newlines in it
+        let input = format!("for var in {}; do [[ -v $var ]] && {}; done 2>/dev/null",
+            env.join(" "), r#"printf 'declare -x %s=%q\n' "$var" "${!var}""#);
+        let output = run_bash_tool(bash, BashRequest { input }, None).await?.output;
+        for line in output.lines().filter(|line| line.starts_with("declare -x ")) {
+            state.push_str(line);
+            state.push('\n');
+        }
+    }
+
+    fs::write(path, state).await.context("Failed to write the state file")
+}
+
+async fn restore_shell_state(bash: &mut Child, path: &Path) -> Result<()> {
+    let state = match fs::read_to_string(path).await {
+        Ok(state) => state,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(error) => return Err(error).context("Failed to read the state file")
+    };
+
+    let stdin = bash.stdin.as_mut().context("Failed to get stdin handle.")?;
+    stdin.write_all(state.as_bytes()).await?;
+    stdin.flush().await.map_err(Into::into)
+}
+
+// a command still running in the foreground holds on to the shell, so the save gives up on it
+// after a while rather than holding up the shutdown
+const SAVE_TIMEOUT: Duration = Duration::from_secs(5);
+
+async fn shutdown_signal() {
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut terminate) => terminate.recv().await,
+            Err(_) => std::future::pending().await
+        }
+    };
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => (),
+        _ = terminate => ()
+    }
+}
+
+#[derive(clap::Parser)]
+#[command(version, about, long_about = None)]
+struct Cli {
+    /// Reply to bash tool calls with the command instead of running it
+    #[arg(long)]
+    echo_commands: bool,
+    /// Where to save the shell's cwd and variables on shutdown for the next startup to restore
+    #[arg(long, value_name = "PATH")]
+    state_file: Option<PathBuf>,
+    /// Environment variable to carry over with --state-file, may be passed multiple times
+    #[arg(long, value_name = "VAR", requires = "state_file")]
+    persist_env: Vec<String>,
+    /// Return a command's output once it has been quiet this long, even if it's still running
+    #[arg(long, value_name = "SECS")]
+    idle_timeout: Option<u64>
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
+    let Cli { echo_commands, state_file, persist_env, idle_timeout } = clap::Parser::parse();
+    let idle_timeout = idle_timeout.map(Duration::from_secs);
     let address = "0.0.0.0:50051".parse()?;
-    let tool_runner = ToolRunner { bash: Mutex::new(spawn_bash()?) };
-    let service = tool_runner_server::ToolRunnerServer::new(tool_runner);
-    Server::builder().add_service(service).serve(address).await.map_err(Into::into)
+    let mut bash = spawn_bash()?;
+
+    // persisting state is best effort, failures shouldn't keep the server from running
+    if let Some(state_file) = &state_file {
+        if let Err(error) = restore_shell_state(&mut bash, state_file).await {
+            eprintln!("Failed to restore shell state: {error:?}");
+        }
+    }
+    let shell_pid = AtomicU32::new(bash.id().unwrap_or_default());
+    let tool_runner =
+        Arc::new(ToolRunner { bash: Mutex::new(bash), shell_pid, echo_commands, idle_timeout });
+    let service = tool_runner_server::ToolRunnerServer::from_arc(Arc::clone(&tool_runner));
+
+    // requests in flight aren't waited on, one running a command could keep the server up forever
+    let serve = Server::builder().add_service(service).serve(address);
+    let served = tokio::select! {
+        result = serve => result.map_err(Into::into),
+        _ = shutdown_signal() => Ok(())
+    };
+
+    if let Some(state_file) = state_file.as_deref().filter(|_| served.is_ok()) {
+        let save = async {
+            let mut bash = tool_runner.bash.lock().await;
+            save_shell_state(&mut bash, state_file, &persist_env).await
+        };
+        match tokio::time::timeout(SAVE_TIMEOUT, save).await {
+            Ok(Ok(())) => (),
+            Ok(Err(error)) => eprintln!("Failed to save shell state: {error:?}"),
+            Err(_) => eprintln!("Failed to save shell state: the shell was still busy after {}s",
+                SAVE_TIMEOUT.as_secs())
+        }
+    }
+    kill_shell(tool_runner.shell_pid.load(Ordering::Relaxed))?;
+    served
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tool_runner_server::ToolRunner as _;
+
+    #[tokio::test]
+    async fn dead_shell_is_reported_as_a_tool_error() {
+        let mut bash = spawn_bash().unwrap();
+        bash.kill().await.unwrap();
+        let tool_runner = ToolRunner {
+            bash: Mutex::new(bash),
+            shell_pid: Default::default(),
+            echo_commands: false,
+            idle_timeout: None
+        };
+
+        let request = Request::new(BashRequest { input: "echo hello".into() });
+        let status = tool_runner.run_bash_tool(request).await.unwrap_err();
+        assert_eq!(status.code(), Code::Unknown);
+        assert!(status.message().starts_with("The shell exited with signal: 9"), "{status:?}");
+        assert!(status.message().contains("reset the server"), "{status:?}");
+    }
+
+    // the ioctl that tells when a command is done isn't available here, so these never finish and
+    // only the shell exiting ends them
+    async fn exit_midway(command: &str) -> String {
+        let mut bash = spawn_bash().unwrap();
+        let pid = bash.id().unwrap();
+        let stdin = bash.stdin.as_mut().unwrap();
+        stdin.write_all(format!("{command}\n").as_bytes()).await.unwrap();
+        let output = collect_output(&mut bash, std::future::pending(), None);
+        let result = tokio::time::timeout(Duration::from_secs(10), output).await;
+        kill_shell(pid).unwrap();
+        result.expect("the shell exiting went unnoticed").unwrap_err().to_string()
+    }
+
+    #[tokio::test]
+    async fn shell_exiting_during_a_command() {
+        // the background sleep keeps the pipes open after bash is gone
+        let error = exit_midway("echo started; sleep 600 & exit 3").await;
+        assert!(error.starts_with("The shell exited with exit status: 3"), "{error}");
+        assert!(error.ends_with("Its last output was:\nstarted\n"), "{error}");
+    }
+
+    #[tokio::test]
+    async fn dead_shell_output_is_truncated() {
+        let command = "head -c 100000 /dev/zero | tr '\\0' x; echo; echo end; exit";
+        let error = exit_midway(command).await;
+        assert!(error.contains("[... 98005 characters truncated ...]\nxxx"), "{error}");
+        assert!(error.ends_with("x\nend\n"), "{error}");
+        assert!(error.len() < 2 * DEAD_SHELL_OUTPUT, "{error}");
+    }
+
+    fn insert(content: &str, after: usize, text: &str) -> Result<(String, Snippet)> {
+        insert_lines(content, after, text, Path::new("/file"))
+    }
+
+    fn changed(snippet: &Snippet) -> (u64, u64, u64) {
+        (snippet.changed_start, snippet.changed_end, snippet.lines_added)
+    }
+
+    #[test]
+    fn insert_multiple_lines() {
+        let (content, snippet) = insert("a\nb\nc\n", 1, "x\ny\n").unwrap();
+        assert_eq!(content, "a\nx\ny\nb\nc\n");
+        assert_eq!(changed(&snippet), (2, 4, 2));
+        assert_eq!(snippet.start, 1);
+        assert_eq!(snippet.lines, ["a", "x", "y", "b", "c", ""]);
+    }
+
+    #[test]
+    fn insert_after_non_ascii() {
+        let (content, snippet) = insert("héllo wörld\n日本語\nend", 2, "ünïcode").unwrap();
+        assert_eq!(content, "héllo wörld\n日本語\nünïcode\nend");
+        assert_eq!(changed(&snippet), (3, 4, 1));
+    }
+
+    #[test]
+    fn insert_at_the_top() {
+        let (content, snippet) = insert("a\nb\n", 0, "x\ny").unwrap();
+        assert_eq!(content, "x\ny\na\nb\n");
+        assert_eq!(changed(&snippet), (1, 3, 2));
+        assert_eq!(snippet.start, 1);
+    }
+
+    #[test]
+    fn insert_after_the_last_line() {
+        let (content, snippet) = insert("a\nb", 2, "x").unwrap();
+        assert_eq!(content, "a\nb\nx");
+        assert_eq!(changed(&snippet), (3, 4, 1));
+
+        // the trailing newline ends line 2, leaving an empty line 3 after it
+        let (content, _) = insert("a\nb\n", 2, "x").unwrap();
+        assert_eq!(content, "a\nb\nx\n");
+        let (content, snippet) = insert("a\nb\n", 3, "x").unwrap();
+        assert_eq!(content, "a\nb\n\nx");
+        assert_eq!(changed(&snippet), (4, 5, 1));
+    }
+
+    #[test]
+    fn insert_out_of_range() {
+        let error = insert("a\nb", 3, "x").unwrap_err();
+        assert_eq!(error.to_string(), "There are only 2 lines in \"/file\"");
+        assert!(insert("a\nb\n", 4, "x").is_err());
+        assert!(insert("", 2, "x").is_err());
+    }
+
+    #[tokio::test]
+    async fn overwrite_counts_the_replaced_lines() {
+        let path = std::env::temp_dir().join(format!("bash-agent-create-{}", std::process::id()));
+        let request = |file_text: &str, overwrite| CreateRequest {
+            path: path.to_string_lossy().into(),
+            file_text: file_text.into(),
+            overwrite
+        };
+
+        let CreateResponse { lines_added, lines_removed } =
+            create(request("a\nb\nc\n", false)).await.unwrap();
+        assert_eq!((lines_added, lines_removed), (3, 0));
+        assert!(create(request("a\n", false)).await.is_err());
+        let CreateResponse { lines_added, lines_removed } =
+            create(request("a\nx\n", true)).await.unwrap();
+        assert_eq!((lines_added, lines_removed), (1, 2));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn snippet_padding_near_the_limits() {
+        let snippet = Snippet::with_padding("a\nb\nc", Some((usize::MAX - 1, usize::MAX)), 4);
+        assert_eq!(snippet.start, usize::MAX as u64 - 4);
+        assert!(snippet.lines.is_empty());
+
+        let snippet = Snippet::with_padding("a\nb\nc", Some((usize::MAX, usize::MAX)), 0);
+        assert_eq!(snippet.start, u64::MAX);
+        assert!(snippet.lines.is_empty());
+
+        let snippet = Snippet::with_padding("a\nb\nc", Some((1, usize::MAX)), usize::MAX);
+        assert_eq!((snippet.start, snippet.lines.len()), (1, 3));
+    }
+
+    #[tokio::test]
+    async fn view_range_near_the_limits() {
+        let path = std::env::temp_dir().join(format!("bash-agent-view-{}", std::process::id()));
+        std::fs::write(&path, "a\nb\nc").unwrap();
+        let view_range = |start, end| ViewRequest {
+            path: path.to_string_lossy().into(),
+            view_range: Some(ViewRange { start, end })
+        };
+
+        let snippet = view(view_range(1, Some(u32::MAX))).await.unwrap();
+        assert_eq!((snippet.start, snippet.lines.len()), (1, 3));
+        let snippet = view(view_range(3, None)).await.unwrap();
+        assert_eq!((snippet.start, snippet.lines.len()), (1, 3));
+
+        let error = view(view_range(u32::MAX, None)).await.unwrap_err();
+        assert!(error.to_string().starts_with("view_range starts at line 4294967295"), "{error}");
+        let error = view(view_range(u32::MAX, Some(u32::MAX))).await.unwrap_err();
+        assert!(error.to_string().contains("only has 3 lines"), "{error}");
+        assert!(view(view_range(3, Some(2))).await.is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
 }
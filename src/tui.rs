@@ -0,0 +1,311 @@
+use std::{pin::Pin, sync::Arc};
+use anyhow::{Context, Result};
+use crossterm::event::{Event, EventStream, KeyCode, KeyEventKind, KeyModifiers};
+use futures::{Stream, StreamExt};
+use ratatui::{
+    layout::{Constraint, Layout},
+    style::{Color, Style, Stylize},
+    text::{Line, Text},
+    widgets::{Block, Paragraph, Wrap},
+    DefaultTerminal,
+    Frame
+};
+use tokio::sync::Notify;
+use crate::{common::{self, Cli, Exchange}, events::{self, AgentEvent}, Outcome};
+
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    Prompt,
+    Response,
+    ToolCall,
+    ToolOutput,
+    Error,
+    Notice
+}
+
+impl Kind {
+    fn style(self) -> Style {
+        match self {
+            Kind::Prompt => Style::new().bold(),
+            Kind::Response => Style::new(),
+            Kind::ToolCall => Style::new().fg(Color::Cyan),
+            Kind::ToolOutput => Style::new().fg(Color::DarkGray),
+            Kind::Error => Style::new().fg(Color::Red),
+            Kind::Notice => Style::new().fg(Color::Yellow).italic()
+        }
+    }
+}
+
+type Events = Pin<Box<dyn Stream<Item = AgentEvent> + Send>>;
+
+// the conversation's lines with how many rows each wraps to, kept between draws since only the
+// last entry can still change
+#[derive(Default)]
+struct WrappedLines {
+    width: u16,
+    lines: Vec<(Line<'static>, usize)>,
+    rows: usize,
+    // how many entries `lines` holds and where the last of them starts
+    entries: usize,
+    last_entry: usize
+}
+
+impl WrappedLines {
+    fn update(&mut self, conversation: &[(Kind, String)], width: u16) {
+        if width != self.width {
+            *self = WrappedLines { width, ..Default::default() };
+        } else if self.entries > 0 {
+            // the last entry may have grown since
+            self.rows -= self.lines.drain(self.last_entry..).map(|(_, rows)| rows).sum::<usize>();
+            self.entries -= 1;
+        }
+
+        for (kind, text) in &conversation[self.entries..] {
+            self.last_entry = self.lines.len();
+            for line in text.lines() {
+                let line = Line::styled(line.replace('\t', "    "), kind.style());
+                let paragraph = Paragraph::new(line.clone()).wrap(Wrap { trim: false });
+                let rows = paragraph.line_count(width);
+                self.rows += rows;
+                self.lines.push((line, rows));
+            }
+        }
+        self.entries = conversation.len();
+    }
+
+    // the lines that fill `height` rows from row `offset` on, and how many rows of the first one
+    // are above `offset`
+    fn visible(&self, offset: u16, height: u16) -> (Vec<Line<'static>>, u16) {
+        let (offset, height) = (usize::from(offset), usize::from(height));
+        let (mut row, mut skipped, mut visible) = (0, 0, vec![]);
+        for (line, rows) in &self.lines {
+            if row >= offset + height {
+                break;
+            }
+            if row + rows > offset {
+                if visible.is_empty() {
+                    skipped = offset - row;
+                }
+                visible.push(line.clone());
+            }
+            row += rows;
+        }
+        (visible, skipped as u16)      // no more than offset
+    }
+}
+
+struct Tui {
+    // parsed once up front, draw and the event handlers run far too often to parse it themselves
+    cli: Cli,
+    conversation: Vec<(Kind, String)>,
+    wrapped: WrappedLines,
+    input: String,
+    exchanges: Vec<Exchange>,
+    running: Option<Events>,
+    // the first line shown, None to follow the end of the conversation as it grows
+    scroll: Option<u16>,
+    // where the last draw left the conversation pane, for scrolling relative to it
+    offset: u16,
+    bottom: u16,
+    page: u16
+}
+
+enum Input {
+    Terminal(Option<std::io::Result<Event>>),
+    Agent(AgentEvent)
+}
+
+impl Tui {
+    fn new(cli: Cli) -> Tui {
+        Tui {
+            cli,
+            conversation: vec![],
+            wrapped: WrappedLines::default(),
+            input: String::new(),
+            exchanges: vec![],
+            running: None,
+            scroll: None,
+            offset: 0,
+            bottom: 0,
+            page: 0
+        }
+    }
+
+    // the transcript gets the same text the REPL would have printed
+    fn append(&mut self, kind: Kind, text: &str) {
+        common::tee(text.as_bytes());
+        match self.conversation.last_mut() {
+            Some((last, existing)) if *last == kind => existing.push_str(text),
+            _ => self.conversation.push((kind, text.into()))
+        }
+    }
+
+    fn end_turn(&mut self) {
+        if let Some(&(kind, ref text)) = self.conversation.last() {
+            if !text.ends_with('\n') {
+                self.append(kind, "\n\n");
+            }
+        }
+    }
+
+    async fn submit(&mut self, cancel: &Arc<Notify>) {
+        let prompt = std::mem::take(&mut self.input).trim().to_string();
+        if prompt.is_empty() {
+            return;
+        }
+        self.append(Kind::Prompt, &format!("> {prompt}\n\n"));
+        self.scroll = None;
+
+        match crate::run_command(&prompt, &mut self.exchanges).await {
+            Ok(Some(output)) => self.append(Kind::Notice, &format!("{}\n\n", output.trim_end())),
+            Ok(None) => {
+                let exchanges = self.exchanges.clone();
+                let events = crate::stream_prompt(prompt, exchanges, Arc::clone(cancel));
+                self.running = Some(Box::pin(events));
+            },
+            Err(error) => self.append(Kind::Error, &format!("{error:#}\n\n"))
+        }
+    }
+
+    fn on_agent_event(&mut self, event: AgentEvent) {
+        match event {
+            AgentEvent::Token(text) => self.append(Kind::Response, &text),
+            AgentEvent::Usage { input_tokens, output_tokens } =>
+                crate::record_usage(input_tokens, output_tokens),
+            AgentEvent::Done(result) => self.on_done(result),
+            // the same as the REPL, errors show up even when quiet
+            AgentEvent::ToolResult { output, is_error: true } => {
+                self.end_turn();
+                self.append(Kind::Error, &format!("{}\n\n", output.trim()));
+            },
+            _ if self.cli.quiet => (),
+            AgentEvent::ToolCall { name, input } => {
+                self.end_turn();
+                self.append(Kind::ToolCall, &events::describe_tool_call(&name, &input));
+            },
+            AgentEvent::ToolResult { output, .. } if !output.trim().is_empty() =>
+                self.append(Kind::ToolOutput, &format!("{}\n\n", output.trim())),
+            AgentEvent::ToolResult { .. } => ()
+        }
+    }
+
+    // unlike the REPL a failed exchange doesn't end the session, it just isn't kept
+    fn on_done(&mut self, result: Result<(Exchange, Outcome)>) {
+        self.running = None;
+        self.end_turn();
+        match result {
+            Ok((exchange, outcome)) => {
+                match outcome {
+                    Outcome::Completed => (),
+                    Outcome::Cancelled => self.append(Kind::Notice, "Cancelled.\n\n"),
+                    Outcome::TimedOut => self.append(Kind::Notice, "Exchange timed out.\n\n")
+                }
+                if !exchange.response.is_empty() {
+                    self.exchanges.push(exchange);
+                }
+            },
+            Err(error) => self.append(Kind::Error, &format!("{error:#}\n\n"))
+        }
+    }
+
+    // returns false once the user asks to exit
+    async fn on_key(&mut self, code: KeyCode, modifiers: KeyModifiers, cancel: &Arc<Notify>)
+    -> bool {
+        let control = modifiers.contains(KeyModifiers::CONTROL);
+        match code {
+            KeyCode::Char('d') if control => return false,
+            KeyCode::Char('c') if control && self.running.is_some() => cancel.notify_waiters(),
+            KeyCode::Char('c') if control => self.input.clear(),
+            KeyCode::Char(_) if control => (),
+            KeyCode::Char(character) => self.input.push(character),
+            KeyCode::Backspace => {
+                self.input.pop();
+            },
+            KeyCode::Enter if self.running.is_none() => self.submit(cancel).await,
+            KeyCode::Up => self.scroll_to(self.offset.saturating_sub(1)),
+            KeyCode::Down => self.scroll_to(self.offset.saturating_add(1)),
+            KeyCode::PageUp => self.scroll_to(self.offset.saturating_sub(self.page)),
+            KeyCode::PageDown => self.scroll_to(self.offset.saturating_add(self.page)),
+            KeyCode::End => self.scroll = None,
+            _ => ()
+        }
+        true
+    }
+
+    // scrolling down to the end starts following the conversation again
+    fn scroll_to(&mut self, line: u16) {
+        self.scroll = (line < self.bottom).then_some(line);
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [conversation, input] =
+            Layout::vertical([Constraint::Min(3), Constraint::Length(3)]).areas(frame.area());
+
+        let height = conversation.height.saturating_sub(2);
+        self.wrapped.update(&self.conversation, conversation.width.saturating_sub(2));
+        self.bottom = u16::try_from(self.wrapped.rows).unwrap_or(u16::MAX).saturating_sub(height);
+        self.offset = self.scroll.map_or(self.bottom, |scroll| scroll.min(self.bottom));
+        self.page = (height / 2).max(1);
+        // only what's on screen is handed to the paragraph, which would wrap everything it gets
+        let (lines, skipped) = self.wrapped.visible(self.offset, height);
+        let title = format!(" {} ", common::current_model(&self.cli));
+        let paragraph = Paragraph::new(Text::from(lines)).wrap(Wrap { trim: false })
+            .block(Block::bordered().title(title)).scroll((skipped, 0));
+        frame.render_widget(paragraph, conversation);
+
+        let title = match self.running {
+            Some(_) => " Ctrl+C cancels the turn, Ctrl+D exits ",
+            None => " Enter sends, PgUp/PgDn scroll, Ctrl+D exits "
+        };
+        // keeps the end of a long prompt and the cursor in view
+        let length = u16::try_from(self.input.chars().count()).unwrap_or(u16::MAX);
+        let scrolled = length.saturating_sub(input.width.saturating_sub(3));
+        let paragraph = Paragraph::new(self.input.as_str()).scroll((0, scrolled));
+        frame.render_widget(paragraph.block(Block::bordered().title(title)), input);
+        frame.set_cursor_position((input.x + 1 + length - scrolled, input.y + 1));
+    }
+
+    async fn run(&mut self, terminal: &mut DefaultTerminal, cancel: &Arc<Notify>) -> Result<()> {
+        let mut terminal_events = EventStream::new();
+        loop {
+            terminal.draw(|frame| self.draw(frame)).context("Failed to draw the interface")?;
+
+            let agent_event = async {
+                match self.running.as_mut() {
+                    Some(events) => events.next().await,
+                    None => std::future::pending().await
+                }
+            };
+            let input = tokio::select! {
+                event = terminal_events.next() => Input::Terminal(event),
+                Some(event) = agent_event => Input::Agent(event)
+            };
+
+            match input {
+                Input::Terminal(None) => break,
+                Input::Terminal(Some(event)) => {
+                    // resizes only need the redraw
+                    let Event::Key(key) = event.context("Failed to read the terminal")? else {
+                        continue;
+                    };
+                    let pressed = key.kind == KeyEventKind::Press;
+                    if pressed && !self.on_key(key.code, key.modifiers, cancel).await {
+                        break;
+                    }
+                },
+                Input::Agent(event) => self.on_agent_event(event)
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Runs the full-screen interface until the user exits with Ctrl+D. The terminal is in raw mode
+/// meanwhile, so Ctrl+C arrives as a key press rather than a signal and cancels through `cancel`
+/// directly.
+pub async fn run(cancel: &Arc<Notify>) -> Result<()> {
+    let mut terminal = ratatui::try_init().context("Failed to set up the terminal")?;
+    let result = Tui::new(clap::Parser::parse()).run(&mut terminal, cancel).await;
+    ratatui::restore();
+    result
+}